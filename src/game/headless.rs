@@ -0,0 +1,289 @@
+use glium;
+use glutin;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use prelude::*;
+use util::camera::*;
+use super::settings::*;
+use super::simulation::*;
+use super::simulation::export;
+use super::simulation::export::MeshFormat;
+use super::simulation::snapshot::Snapshot;
+
+const DEFAULT_STEPS: usize = 600;
+const DEFAULT_OUT_DIR: &str = "headless_out";
+const STATS_FILE: &str = "stats.csv";
+const FINAL_MESH_FILE: &str = "final.obj";
+const FINAL_SNAPSHOT_FILE: &str = "final.dss";
+const FRAMES_DIR: &str = "frames";
+const READBACK_FILE: &str = "readback.csv";
+/// Grid sizes that `--readback-bench` measures
+const READBACK_BENCH_DIMS: [usize; 2] = [64, 128];
+
+pub const HEADLESS_USAGE: &str =
+    "usage: ds-sim --headless [--steps N] [--out DIR] [--load SNAPSHOT] [--render] \
+     [--explode START..END]... [--readback-bench]";
+
+/// Options for running the simulation without a window or a GL context.
+#[derive(Debug)]
+pub struct HeadlessOptions {
+    /// Number of fixed steps to simulate
+    pub steps: usize,
+    /// Directory for the statistics and meshes
+    pub out_dir: PathBuf,
+    /// Step ranges [start, end[ during which the laser is fired
+    pub explosions: Vec<(usize, usize)>,
+    /// Snapshot to continue from instead of building the simulation from the settings
+    pub snapshot: Option<PathBuf>,
+    /// Render the frames as PNGs with a headless GL context
+    pub render: bool,
+    /// Measure the copies from the device instead of running the simulation once
+    pub readback_bench: bool,
+}
+
+impl HeadlessOptions {
+    /// Parses the options from the command line arguments, excluding the program name.
+    pub fn from_args(args: &[String]) -> Result<HeadlessOptions, String> {
+        let mut opts = HeadlessOptions {
+            steps: DEFAULT_STEPS,
+            out_dir: PathBuf::from(DEFAULT_OUT_DIR),
+            explosions: vec![],
+            snapshot: None,
+            render: false,
+            readback_bench: false,
+        };
+
+        let mut it = args.iter();
+        while let Some(arg) = it.next() {
+            match arg.as_str() {
+                "--headless" => {}
+                "--steps" => {
+                    opts.steps = next_value(&mut it, arg)?
+                        .parse()
+                        .map_err(|e| format!("invalid --steps: {}", e))?;
+                }
+                "--out" => {
+                    opts.out_dir = PathBuf::from(next_value(&mut it, arg)?);
+                }
+                "--render" => opts.render = true,
+                "--readback-bench" => opts.readback_bench = true,
+                "--load" => {
+                    opts.snapshot = Some(PathBuf::from(next_value(&mut it, arg)?));
+                }
+                "--explode" => {
+                    let range = next_value(&mut it, arg)?;
+                    opts.explosions.push(parse_range(range)?);
+                }
+                other => return Err(format!("unknown argument: {}", other)),
+            }
+        }
+        Ok(opts)
+    }
+}
+
+fn next_value<'a, I>(it: &mut I, arg: &str) -> Result<&'a String, String>
+where
+    I: Iterator<Item = &'a String>,
+{
+    it.next().ok_or_else(|| format!("missing value for {}", arg))
+}
+
+/// Parses a step range in the form "START..END".
+fn parse_range(s: &str) -> Result<(usize, usize), String> {
+    let mut parts = s.splitn(2, "..");
+    let start = parts.next().and_then(|p| p.parse().ok());
+    let end = parts.next().and_then(|p| p.parse().ok());
+    match (start, end) {
+        (Some(start), Some(end)) if start <= end => Ok((start, end)),
+        _ => Err(format!("invalid step range \"{}\", expected START..END", s)),
+    }
+}
+
+/// Runs the simulation for a fixed number of steps without opening a window. Writes per-step
+/// statistics as CSV, the final surface as OBJ and the final state as a snapshot into the output
+/// directory.
+pub fn run_headless(opts: &HeadlessOptions) -> io::Result<()> {
+    info!("running headless: {:?}", opts);
+    if opts.readback_bench {
+        return run_readback_bench(opts);
+    }
+
+    let snapshot = match opts.snapshot {
+        Some(ref path) => Some(Snapshot::load(path)?),
+        None => None,
+    };
+    // A snapshot carries the settings it was made with
+    let cfg = match snapshot {
+        Some(ref snapshot) => snapshot.settings.clone(),
+        None => Settings::new(),
+    };
+    let fixed_dt = (1.0 / cfg.fixed_fps) as f32;
+    let mut geom_gen = match snapshot {
+        Some(ref snapshot) => GeometryGen::from_snapshot(snapshot, fixed_dt),
+        None => GeometryGen::new(&cfg, fixed_dt),
+    };
+
+    fs::create_dir_all(&opts.out_dir)?;
+    let mut stats = io::BufWriter::new(fs::File::create(opts.out_dir.join(STATS_FILE))?);
+    writeln!(stats, "{}", FieldStats::CSV_HEADER)?;
+
+    // `--render` captures every step into a video unless an interval is set
+    let capture_interval = cfg.capture_interval.max(1);
+    let mut capture = if opts.render {
+        Some(HeadlessCapture::new(&cfg)?)
+    } else {
+        None
+    };
+
+    let export_format = MeshFormat::from_name(&cfg.export_format);
+    for step in 0..opts.steps {
+        let laser = opts.explosions
+            .iter()
+            .any(|&(start, end)| step >= start && step < end);
+        geom_gen.explode(laser);
+        geom_gen.fixed_update(fixed_dt);
+        writeln!(stats, "{}", geom_gen.stats().to_csv())?;
+
+        // The surface sequence goes next to the other results
+        let frame = geom_gen.frame_count();
+        if cfg.export_interval > 0 && frame % cfg.export_interval == 0 {
+            let path = export::surface_path(&opts.out_dir, frame, export_format);
+            geom_gen.export_surface(&path, export_format)?;
+        }
+        if cfg.volume_export_interval > 0 && frame % cfg.volume_export_interval == 0 {
+            geom_gen.export_volume(export::volume_path(&opts.out_dir, frame))?;
+        }
+        if let Some(ref mut capture) = capture {
+            if frame % capture_interval == 0 {
+                let path = frame_path(opts.out_dir.join(FRAMES_DIR), frame);
+                capture.capture(&mut geom_gen, &path)?;
+            }
+        }
+    }
+
+    let (vertices, indices) = geom_gen.extract_mesh();
+    let mesh_path = opts.out_dir.join(FINAL_MESH_FILE);
+    export::save_obj(&mesh_path, &vertices, &indices)?;
+    geom_gen
+        .snapshot(&cfg)
+        .save(opts.out_dir.join(FINAL_SNAPSHOT_FILE))?;
+    info!(
+        "headless run finished after {} steps, wrote {}",
+        opts.steps,
+        mesh_path.display()
+    );
+    // The CPU extractor is the reference for marching cubes on the device
+    let (mut cpu_vertices, mut cpu_indices) = (vec![], vec![]);
+    geom_gen.extract_mesh_cpu(&mut cpu_vertices, &mut cpu_indices);
+    info!(
+        "final surface has {} triangles, {} with the CPU extractor",
+        indices.len() / 3,
+        cpu_indices.len() / 3
+    );
+    let readback = geom_gen.readback_stats();
+    info!(
+        "read back the buffers {} times, {:.1} MB in {:.1} ms",
+        readback.count,
+        readback.bytes as f64 * 1e-6,
+        readback.millis()
+    );
+
+    Ok(())
+}
+
+/// Measures the copies from the device at each grid size of `READBACK_BENCH_DIMS`: once with all
+/// of the fields copied after every step, as before the buffers were kept on the device, and once
+/// with only the mass copied, as for a drawn frame. Writes the results as CSV into the output
+/// directory.
+fn run_readback_bench(opts: &HeadlessOptions) -> io::Result<()> {
+    fs::create_dir_all(&opts.out_dir)?;
+    let path = opts.out_dir.join(READBACK_FILE);
+    let mut results = io::BufWriter::new(fs::File::create(&path)?);
+    writeln!(results, "dim,mode,steps,copies,megabytes,millis,millis_per_step")?;
+
+    let mut cfg = Settings::new();
+    let fixed_dt = (1.0 / cfg.fixed_fps) as f32;
+    for &dim in READBACK_BENCH_DIMS.iter() {
+        cfg.scalar_field_dim = dim;
+        for &(mode, all) in &[("all_fields", true), ("mass_only", false)] {
+            let mut geom_gen = GeometryGen::new(&cfg, fixed_dt);
+            for _ in 0..opts.steps {
+                geom_gen.fixed_update(fixed_dt);
+                geom_gen.read_back(all);
+            }
+            let readback = geom_gen.readback_stats();
+            info!(
+                "dim {}, {}: read back {} times, {:.1} MB in {:.1} ms",
+                dim,
+                mode,
+                readback.count,
+                readback.bytes as f64 * 1e-6,
+                readback.millis()
+            );
+            writeln!(
+                results,
+                "{},{},{},{},{},{},{}",
+                dim,
+                mode,
+                opts.steps,
+                readback.count,
+                readback.bytes as f64 * 1e-6,
+                readback.millis(),
+                readback.millis() / opts.steps.max(1) as f64
+            )?;
+        }
+    }
+    info!("wrote the readback benchmark to {}", path.display());
+    Ok(())
+}
+
+/// Renders the surface offscreen without a window, eg. with Mesa's software rasterizer on
+/// machines without a GPU.
+struct HeadlessCapture {
+    facade: glium::HeadlessRenderer,
+    renderer: Renderer,
+    camera: RotationalCamera,
+    m_transform: Decomposedf,
+    size: (u32, u32),
+}
+
+impl HeadlessCapture {
+    fn new(cfg: &Settings) -> io::Result<HeadlessCapture> {
+        let size = (cfg.capture_width, cfg.capture_height);
+        let context = glutin::HeadlessRendererBuilder::new(size.0, size.1)
+            .build()
+            .map_err(|e| gl_error("cannot create a headless GL context", e))?;
+        let facade = glium::HeadlessRenderer::new(context)
+            .map_err(|e| gl_error("incompatible OpenGL", e))?;
+        let renderer = Renderer::new(&facade, cfg);
+        let mut camera = default_camera(size.0 as f32 / size.1 as f32);
+        camera.late_update();
+
+        Ok(HeadlessCapture {
+            facade,
+            renderer,
+            camera,
+            m_transform: model_transform(),
+            size,
+        })
+    }
+
+    fn capture(&mut self, geom_gen: &mut GeometryGen, path: &Path) -> io::Result<()> {
+        self.renderer.update_mesh(geom_gen, &self.facade);
+        self.renderer
+            .capture(
+                &self.facade,
+                &mut self.camera,
+                &self.m_transform,
+                self.size,
+                path,
+            )
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+fn gl_error<E: ::std::fmt::Debug>(what: &str, e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{}: {:?}", what, e))
+}
@@ -1,13 +1,19 @@
+mod headless;
 mod settings;
 mod simulation;
 
 use glium::*;
 use glutin::*;
 use handle_events::*;
+use util::FileWatcher;
+use recording::*;
 use std::time::{Duration, Instant};
 use std::ops::Deref;
 use self::simulation::*;
 use self::settings::*;
+pub use self::headless::*;
+pub use self::settings::Settings;
+pub use self::simulation::print_devices;
 
 pub enum GameFn {
     StateFn(fn(&mut GameStruct) -> GameFn),
@@ -48,16 +54,29 @@ pub struct GameStruct {
     events_loop: EventsLoop,
     fps_calc: FpsCalculator,
     print_timer: f32,
+    input_mode: InputMode,
+    /// Kept across rebuilds of the simulation so that a session is a single recording
+    recorder: Option<Recorder>,
+    player: Option<Player>,
+    /// The fixed step of the session, continued across rebuilds
+    step: usize,
 }
 
 const PRINT_INTERVAL: f32 = 2f32;
+/// How often the config files and in dev mode the shaders and kernels are checked for changes,
+/// in seconds
+const SETTINGS_POLL_INTERVAL: f32 = 0.5f32;
 impl GameStruct {
-    pub fn new(events_loop: EventsLoop, display: Display) -> GameStruct {
+    pub fn new(events_loop: EventsLoop, display: Display, input_mode: InputMode) -> GameStruct {
         GameStruct {
             display,
             events_loop,
             fps_calc: FpsCalculator::new(),
             print_timer: PRINT_INTERVAL,
+            input_mode,
+            recorder: None,
+            player: None,
+            step: 0,
         }
     }
 
@@ -65,6 +84,21 @@ impl GameStruct {
     pub fn init(&mut self) -> GameFn {
         info!("GameStruct::init");
 
+        match self.input_mode.clone() {
+            InputMode::Live => {}
+            InputMode::Record(path) => {
+                info!("recording the actions to {}", path.display());
+                self.recorder = Some(Recorder::new(path));
+            }
+            InputMode::Replay(path) => match Player::load(&path) {
+                Ok(p) => {
+                    info!("replaying the actions from {}", path.display());
+                    self.player = Some(p);
+                }
+                Err(e) => error!("cannot load the recording {}: {}", path.display(), e),
+            },
+        }
+
         // After initialization, go to simulation
         GameFn::new(Self::simulation)
     }
@@ -85,11 +119,19 @@ impl GameStruct {
             1f32 / fixed_timestep_s
         );
 
-        let mut simulation = Simulation::new(fixed_timestep_s, cfg, &mut self.display);
+        let mut simulation = Simulation::new(fixed_timestep_s, cfg.clone(), &mut self.display);
+        let mut cfg = cfg;
+        let mut settings_watcher = FileWatcher::new(Settings::config_files());
+        let mut settings_timer = SETTINGS_POLL_INTERVAL;
 
         // Update eg. camera before starting the main loop
         simulation.late_update(&mut self.display);
 
+        // Recording and replaying apply the actions and move the camera on the fixed steps so
+        // that they don't depend on the frame rate
+        let lockstep = self.recorder.is_some() || self.player.is_some();
+        let mut pending_actions = vec![];
+
         // Fixed delta-time accumulator
         let mut fdt_accumulator = Duration::new(0, 0);
         let mut last_frame_time = Instant::now();
@@ -100,11 +142,14 @@ impl GameStruct {
             // Collect events from window and devices
             let user_actions = poll_events(&mut self.events_loop);
 
-            // Handle eg. resizing and exiting window
-            if let Some(cmd) = simulation.process_events(&user_actions) {
+            if lockstep {
+                pending_actions.extend(user_actions);
+            } else if let Some(cmd) = simulation.process_events(&user_actions) {
+                // Handle eg. resizing and exiting window
                 match cmd {
                     ProgramCommand::Exit => return GameFn::exit(),
                     ProgramCommand::RefreshSimulation => return GameFn::new(Self::simulation),
+                    ProgramCommand::SettingsLoaded(new_cfg) => cfg = new_cfg,
                 }
             }
 
@@ -120,19 +165,90 @@ impl GameStruct {
                     + fixed_deltatime.subsec_nanos() as f64 * 1e-9)
                     as f32;
 
+                if lockstep {
+                    let mut actions = vec![];
+                    for action in pending_actions.drain(..) {
+                        match (&self.player, action) {
+                            // Only the window itself is controlled while replaying
+                            (&Some(_), Action::Exit) => actions.push(Action::Exit),
+                            (&Some(_), Action::SetAspect(a)) => actions.push(Action::SetAspect(a)),
+                            (&Some(_), _) => {}
+                            (&None, action) => actions.push(action),
+                        }
+                    }
+                    if let Some(ref mut player) = self.player {
+                        actions.extend(player.actions_at(self.step));
+                    }
+                    if let Some(ref mut recorder) = self.recorder {
+                        recorder.record(self.step, &actions);
+                    }
+                    if let Some(cmd) = simulation.process_events(&actions) {
+                        match cmd {
+                            ProgramCommand::Exit => return GameFn::exit(),
+                            ProgramCommand::RefreshSimulation => {
+                                return GameFn::new(Self::simulation)
+                            }
+                            ProgramCommand::SettingsLoaded(new_cfg) => cfg = new_cfg,
+                        }
+                    }
+                }
+
                 simulation.fixed_update(fixed_dt);
+
+                if lockstep {
+                    simulation.update(fixed_dt);
+                    simulation.update_camera();
+                    self.step += 1;
+                }
             }
             let dt = (dt.as_secs() as f64 + dt.subsec_nanos() as f64 * 1e-9) as f32;
-            simulation.update(dt);
+            if !lockstep {
+                simulation.update(dt);
+            }
 
             // Measure fps
             self.fps_calc.store_dt(dt);
 
+            // Reload the settings when the files change
+            settings_timer -= dt;
+            if settings_timer <= 0f32 {
+                settings_timer += SETTINGS_POLL_INTERVAL;
+                simulation.hot_reload(&self.display);
+                if settings_watcher.changed() {
+                    match Settings::load() {
+                        Ok(new_cfg) => match cfg.change_to(&new_cfg) {
+                            SettingsChange::Unchanged => {}
+                            SettingsChange::Runtime(keys) => {
+                                info!("settings changed at runtime: {}", keys.join(", "));
+                                simulation.apply_settings(new_cfg.clone(), &self.display);
+                                cfg = new_cfg;
+                            }
+                            SettingsChange::Rebuild(keys) => {
+                                info!("settings changed, rebuilding: {}", keys.join(", "));
+                                // Replaying refreshes on the same step with the new settings
+                                if let Some(ref mut recorder) = self.recorder {
+                                    recorder.record(self.step, &[Action::Refresh]);
+                                }
+                                return GameFn::new(Self::simulation);
+                            }
+                        },
+                        Err(e) => warn!("cannot reload the settings, keeping the old: {}", e),
+                    }
+                }
+            }
+
             // Print diagnostics periodically
             self.print_timer -= dt;
             if self.print_timer <= 0f32 {
                 self.print_timer += PRINT_INTERVAL;
-                debug!("FPS: {:.1}", self.fps_calc.fps());
+                let readback = simulation.readback_stats();
+                debug!(
+                    "FPS: {:.1}, readbacks: {} ({:.1} MB, {:.1} ms)",
+                    self.fps_calc.fps(),
+                    readback.count,
+                    readback.bytes as f64 * 1e-6,
+                    readback.millis()
+                );
             }
 
             // Update eg. camera
@@ -1,5 +1,5 @@
 use std::env;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::io::prelude::*;
 use config::*;
 use std::fs;
@@ -15,6 +15,71 @@ pub struct Settings {
     pub planet_texture: String,
     pub polar_texture: String,
     pub laser_strength: f32,
+    /// Compute backend for the liquid simulation: "opencl" or "cpu"
+    pub simulation_backend: String,
+    /// Temperature loss due to "radiation" per second
+    pub temperature_radiate: f32,
+    /// Temperature diffusion coefficient between neighboring cells
+    pub temperature_diffuse: f32,
+    /// Temperature at which solid matter melts and starts to flow
+    pub melting_temperature: f32,
+    /// Mass of a launched impactor in units of one full cell
+    pub impactor_mass: f32,
+    /// Radius of a launched impactor, the planet spans [-1, 1]
+    pub impactor_radius: f32,
+    /// Launch speed of an impactor in the planet's units per second
+    pub impactor_speed: f32,
+    /// Gravitational constant for the self-gravity of the planet. A cell holds its depth below
+    /// the surface times its volume of mass, in the [0, 1] simulation space. The default pulls
+    /// about as hard at the surface of the default planet as the old fixed central pull did.
+    pub gravity_constant: f32,
+    /// Behavior of the simulation grid border: "open", "closed" or "periodic"
+    pub boundary: String,
+    /// Seed for every random number generator used to build the planet
+    pub seed: u64,
+    /// Height of the terrain relative to the planet radius
+    pub terrain_amplitude: f32,
+    /// Frequency of the largest terrain features, eg. continents
+    pub terrain_frequency: f32,
+    /// Number of noise layers in the terrain, more layers add smaller details
+    pub terrain_octaves: u32,
+    /// Number of craters on the initial planet
+    pub terrain_craters: usize,
+    /// YAML scene of signed distance fields to use as the initial bodies instead of the
+    /// generated planet, eg. "data/scene/moon.yaml". Empty uses the planet.
+    pub scene: String,
+    /// Closed Wavefront OBJ mesh to voxelize into the initial body, eg. "data/mesh/teapot.obj".
+    /// Takes precedence over `scene`, empty disables.
+    pub mesh: String,
+    /// File that Ctrl+S saves the simulation state into and Ctrl+L restores it from
+    pub snapshot_file: String,
+    /// File format of the exported surfaces: "obj", "ply" or "stl"
+    pub export_format: String,
+    /// Directory that Ctrl+E, Ctrl+V and the periodic exports write into
+    pub export_dir: String,
+    /// Export the surface every N simulation steps, 0 disables
+    pub export_interval: usize,
+    /// Export the volumes as VTK every N simulation steps, 0 disables
+    pub volume_export_interval: usize,
+    /// Save every Nth drawn frame as a PNG into `capture_dir`, 0 disables. Headless runs with
+    /// `--render` capture every Nth step, and every step while this is 0.
+    pub capture_interval: usize,
+    pub capture_dir: String,
+    /// Resolution of the captured frames in both windowed and headless runs, independent of the
+    /// window
+    pub capture_width: u32,
+    pub capture_height: u32,
+    /// Recompile the shaders in `src/shader` and the OpenCL kernel when they change
+    pub dev_mode: bool,
+    /// OpenCL platform by index or name, eg. "NVIDIA" or "Portable Computing Language". Empty
+    /// selects the first one. `--list-devices` prints the available platforms and devices.
+    pub opencl_platform: String,
+    /// OpenCL device of the platform by index, name or type ("cpu", "gpu" or "accelerator").
+    /// Empty selects the first one.
+    pub opencl_device: String,
+    /// Extract the surface with marching cubes on the OpenCL device instead of copying the mass
+    /// to the host for it. Ignored by the CPU backend.
+    pub device_marching_cubes: bool,
 }
 
 impl Default for Settings {
@@ -25,7 +90,36 @@ impl Default for Settings {
             fixed_fps: 60f64,
             planet_texture: "local_data/planet_textures_by_kakarotti/planet1.jpg".to_owned(),
             polar_texture: "data/texture/ice.png".to_owned(),
-            laser_strength: 5f32
+            laser_strength: 5f32,
+            simulation_backend: "opencl".to_owned(),
+            temperature_radiate: 0.1f32,
+            temperature_diffuse: 0.1f32,
+            melting_temperature: 1f32,
+            impactor_mass: 8f32,
+            impactor_radius: 0.1f32,
+            impactor_speed: 1f32,
+            gravity_constant: 250f32,
+            boundary: "closed".to_owned(),
+            seed: 0,
+            terrain_amplitude: 0.08f32,
+            terrain_frequency: 2f32,
+            terrain_octaves: 5,
+            terrain_craters: 8,
+            scene: "".to_owned(),
+            mesh: "".to_owned(),
+            snapshot_file: "local_data/snapshot.dss".to_owned(),
+            export_format: "obj".to_owned(),
+            export_dir: "local_data/export".to_owned(),
+            export_interval: 0,
+            volume_export_interval: 0,
+            capture_interval: 0,
+            capture_dir: "local_data/frames".to_owned(),
+            capture_width: 1920,
+            capture_height: 1080,
+            dev_mode: false,
+            opencl_platform: "".to_owned(),
+            opencl_device: "".to_owned(),
+            device_marching_cubes: true,
         }
     }
 }
@@ -40,8 +134,42 @@ lazy_static! {
     static ref DEFAULT: Settings = Settings::default();
 }
 
+/// Settings that can change while the simulation is running, changes to any other settings
+/// rebuild the simulation
+const RUNTIME_KEYS: &[&str] = &[
+    "render_cube",
+    "planet_texture",
+    "polar_texture",
+    "laser_strength",
+    "snapshot_file",
+    "export_format",
+    "export_dir",
+    "export_interval",
+    "volume_export_interval",
+    "capture_interval",
+    "capture_dir",
+    "capture_width",
+    "capture_height",
+];
+
+/// How the running simulation has to react to changed settings
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SettingsChange {
+    Unchanged,
+    /// Only settings that can be applied immediately changed
+    Runtime(Vec<String>),
+    /// Some of the changed settings need a rebuild of the simulation
+    Rebuild(Vec<String>),
+}
+
 impl Settings {
     pub fn new() -> Self {
+        Settings::load().expect("cannot load config")
+    }
+
+    /// Reads the settings from the config files, returning an error rather than panicking on
+    /// an invalid file.
+    pub fn load() -> Result<Self, ConfigError> {
         let mut cfg = Config::new();
 
         cfg.merge(DEFAULT.clone()).unwrap();
@@ -60,34 +188,53 @@ impl Settings {
 
         // Read and merge the per-program config
         let per_program = File::with_name(&per_program_path);
-        cfg.merge(per_program)
-            .expect("cannot merge per-program config");
+        cfg.merge(per_program)?;
 
         // Attempt to read the per-user settings
         let per_user_path = format!("{}/{}", CFG_DIR, USER_CFG);
         let per_user_cfg = File::with_name(&per_user_path).required(false);
-        cfg.merge(per_user_cfg)
-            .expect("cannot merge per-user config");
+        cfg.merge(per_user_cfg)?;
 
         // Attempt to read the environment variable settings
         let env_cfg = env::var(CFG_ENV_KEY);
         if let Ok(s) = env_cfg {
-            cfg.merge(File::with_name(&format!("{}", s)).required(false))
-                .expect("cannot merge env to config");
+            cfg.merge(File::with_name(&format!("{}", s)).required(false))?;
         }
 
-        cfg.try_into().expect("cannot unwrap config")
+        cfg.try_into()
     }
-}
 
-impl Source for Settings {
-    fn clone_into_box(&self) -> Box<Source + Send + Sync> {
-        Box::new(self.clone())
+    /// The config files that the settings are read from: the per-program and the per-user config
+    /// and the file in `DS_CFG`.
+    pub fn config_files() -> Vec<PathBuf> {
+        let mut paths = vec![
+            PathBuf::from(format!("{}/{}", CFG_DIR, PROGRAM_CFG)),
+            PathBuf::from(format!("{}/{}", CFG_DIR, USER_CFG)),
+        ];
+        if let Ok(s) = env::var(CFG_ENV_KEY) {
+            paths.push(PathBuf::from(s));
+        }
+        paths
     }
-    fn collect(&self) -> Result<HashMap<String, Value>, ConfigError> {
-        match serde_yaml::from_str(&serde_yaml::to_string(self).unwrap()) {
-            Ok(hm) => Ok(hm),
-            Err(_e) => panic!(),
+
+    /// Compares the settings to `new` and classifies the change.
+    pub fn change_to(&self, new: &Settings) -> SettingsChange {
+        let as_map = |s: &Settings| -> HashMap<String, serde_yaml::Value> {
+            serde_yaml::from_str(&serde_yaml::to_string(s).unwrap()).unwrap()
+        };
+        let (old, new) = (as_map(self), as_map(new));
+        let mut changed: Vec<String> = new.iter()
+            .filter(|&(key, value)| old.get(key) != Some(value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        changed.sort();
+
+        if changed.is_empty() {
+            SettingsChange::Unchanged
+        } else if changed.iter().all(|key| RUNTIME_KEYS.contains(&key.as_str())) {
+            SettingsChange::Runtime(changed)
+        } else {
+            SettingsChange::Rebuild(changed)
         }
     }
 }
@@ -0,0 +1,340 @@
+use cgmath::InnerSpace;
+use prelude::*;
+use super::cpu_liquid_sim::CpuLiquidSim;
+use super::ocl_liquid_sim::OclLiquidSim;
+
+/// Read-only view into one half of the double-buffered simulation state.
+pub struct Fields<'a> {
+    pub mass: &'a [f32],
+    /// Flows are float3's padded to four floats per cell, as in OpenCL
+    pub flow: &'a [f32],
+    pub temp: &'a [f32],
+}
+
+/// Writable view into one half of the double-buffered simulation state.
+pub struct FieldsMut<'a> {
+    pub mass: &'a mut [f32],
+    /// Flows are float3's padded to four floats per cell, as in OpenCL
+    pub flow: &'a mut [f32],
+    pub temp: &'a mut [f32],
+}
+
+/// Number of floats reserved per cell for a float3 (OpenCL aligns float3 as float4)
+pub const FLOW_STRIDE: usize = 4;
+
+/// Static parameters shared by all simulation backends.
+#[derive(Clone, Debug)]
+pub struct SimulationParams {
+    /// Scalar field side length
+    pub dim: usize,
+    pub fixed_dt: f32,
+    /// Distance between two cell-centers in simulation space (0..1), see `physics_cell_dist`
+    pub cell_dist: f32,
+    /// Temperature loss due to "radiation" per second
+    pub radiate: f32,
+    /// Temperature diffusion coefficient between neighboring cells
+    pub diffuse: f32,
+    /// Temperature at which solid matter melts and below which it refreezes
+    pub melt_temp: f32,
+    /// Gravitational constant for the self-gravity of the mass
+    pub gravity_constant: f32,
+    pub boundary: Boundary,
+    /// OpenCL platform selection, see `opencl_platform` in the settings
+    pub platform: String,
+    /// OpenCL device selection, see `opencl_device` in the settings
+    pub device: String,
+    /// Extract the surface on the OpenCL device, see `device_marching_cubes` in the settings
+    pub marching_cubes: bool,
+}
+
+/// The cell spacing that the liquid physics runs with. The flows were tuned with `1 / (dim - 2)`
+/// before the field was sampled at the cell centers, so the physics keeps it while the sampling
+/// and the mesh use `1 / (dim - 1)`.
+pub fn physics_cell_dist(dim: usize) -> f32 {
+    1f32 / (dim - 2) as f32
+}
+
+/// How the border of the simulation grid behaves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Boundary {
+    /// The border is kept empty, mass that flows into it is deleted and counted as ejected
+    Open,
+    /// The border mirrors the neighboring cells and no mass flows through it
+    Closed,
+    /// The grid wraps around, there is no border
+    Periodic,
+}
+
+impl Boundary {
+    /// Parses the boundary mode from settings; unknown names fall back to closed.
+    pub fn from_name(name: &str) -> Boundary {
+        match name.to_lowercase().as_str() {
+            "open" => Boundary::Open,
+            "closed" => Boundary::Closed,
+            "periodic" => Boundary::Periodic,
+            other => {
+                warn!("unknown boundary mode \"{}\", using closed", other);
+                Boundary::Closed
+            }
+        }
+    }
+
+    /// The matching BOUNDARY_* value in `cl/liquid_sim.cl`
+    pub fn cl_value(&self) -> u32 {
+        match *self {
+            Boundary::Open => 0,
+            Boundary::Closed => 1,
+            Boundary::Periodic => 2,
+        }
+    }
+}
+
+/// A beam that heats the cells close to its axis, eg. the laser. Positions are in cell units.
+#[derive(Clone, Debug)]
+pub struct HeatBeam {
+    pub origin: Vector3f,
+    /// Normalized direction of the beam
+    pub dir: Vector3f,
+    /// Distance from the beam axis where the heat falls off to zero
+    pub radius: f32,
+    /// How far the beam reaches from the origin
+    pub depth: f32,
+    /// Heat added per second at the beam axis
+    pub power: f32,
+}
+
+impl HeatBeam {
+    /// Heat added per second into the cell at `pos`, see `inject_heat` in `cl/liquid_sim.cl`.
+    pub fn heat_at(&self, pos: Vector3f) -> f32 {
+        // Only heat cells in front of the beam origin
+        let t = (pos - self.origin).dot(self.dir);
+        if t < 0f32 || t > self.depth {
+            return 0f32;
+        }
+        let dist = (pos - (self.origin + self.dir * t)).magnitude();
+        if dist < self.radius {
+            self.power * (1f32 - dist / self.radius)
+        } else {
+            0f32
+        }
+    }
+}
+
+/// A compute backend for the liquid simulation.
+pub trait SimulationBackend {
+    /// Human-readable name for logging
+    fn name(&self) -> &'static str;
+
+    /// Runs one fixed step of the liquid simulation. Reads the cells of `src` and writes the
+    /// next state into `dst`. The host-side buffers for `frame_count` are passed in by the
+    /// caller; backends that keep their own copies of the data may ignore them and leave `dst`
+    /// stale until `download`.
+    fn simulate(&mut self, src: Fields, dst: FieldsMut, frame_count: usize);
+
+    /// Adds the heat of `beam` for one fixed step into the temperatures that are read on
+    /// `frame_count`. `temp` is the matching host-side buffer.
+    fn inject_heat(&mut self, temp: &mut [f32], beam: &HeatBeam, frame_count: usize);
+
+    /// Called after the host-side buffers that are read on `frame_count` have been modified.
+    /// Backends that keep their own copies of the data copy `src` over them.
+    fn upload(&mut self, src: Fields, frame_count: usize);
+
+    /// Copies the fields that are read on `frame_count` into the host-side buffers `dst`.
+    /// Returns the number of bytes copied; backends that work on the host-side buffers have
+    /// nothing to copy.
+    fn download(&mut self, _dst: FieldsMut, _frame_count: usize) -> usize {
+        0
+    }
+
+    /// The same as `download`, but only for the mass, eg. for meshing.
+    fn download_mass(&mut self, _mass: &mut [f32], _frame_count: usize) -> usize {
+        0
+    }
+
+    /// Replaces `vertices` with the surface of the mass that is read on `frame_count`, three
+    /// vertices per triangle with interleaved positions and normals in the [0, 1] sample space.
+    /// Returns false when the backend can't, and the surface is extracted from the host-side
+    /// buffers instead.
+    fn extract_surface(&mut self, _vertices: &mut Vec<f32>, _frame_count: usize) -> bool {
+        false
+    }
+
+    /// Total mass deleted by open boundaries since the start, in units of one full cell, and the
+    /// number of bytes copied for it like in `download`.
+    fn ejected_mass(&mut self) -> (f32, usize);
+
+    /// Recompiles the kernels from their source files and keeps the state. On an error the
+    /// previous kernels are kept. Backends without runtime-compiled kernels do nothing.
+    fn reload(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// The available backends, as named in the settings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackendKind {
+    OpenCl,
+    Cpu,
+}
+
+impl BackendKind {
+    /// Parses the backend name from settings; unknown names fall back to OpenCL.
+    pub fn from_name(name: &str) -> BackendKind {
+        match name.to_lowercase().as_str() {
+            "opencl" | "ocl" => BackendKind::OpenCl,
+            "cpu" => BackendKind::Cpu,
+            other => {
+                warn!("unknown simulation backend \"{}\", using OpenCL", other);
+                BackendKind::OpenCl
+            }
+        }
+    }
+}
+
+/// Creates the requested backend. If OpenCL cannot be initialized, falls back to the CPU backend.
+///
+/// `host_bufs` are the host-side double buffers that the OpenCL buffers are initialized from.
+pub fn create_backend(
+    kind: BackendKind,
+    params: &SimulationParams,
+    host_bufs: [Fields; 2],
+) -> Box<SimulationBackend> {
+    match kind {
+        BackendKind::OpenCl => {
+            debug!("initializing OpenCL");
+            match OclLiquidSim::new(params, host_bufs) {
+                Ok(sim) => {
+                    debug!("OpenCL init success");
+                    Box::new(sim)
+                }
+                Err(e) => {
+                    warn!("OpenCL init failed, falling back to the CPU backend: {}", e);
+                    Box::new(CpuLiquidSim::new(params))
+                }
+            }
+        }
+        BackendKind::Cpu => Box::new(CpuLiquidSim::new(params)),
+    }
+}
+
+/// Normalize the marching-cube related ranges from {-1, 1} where mass is -1 and no-mass is 1
+/// into {0, 1} where mass is 1 and 0 is no-mass
+pub fn normf(x: f32) -> f32 {
+    (-x + 1f32) * 0.5f32
+}
+
+/// The same as `normf`, but backwards
+pub fn denormf(x: f32) -> f32 {
+    -(x * 2f32 - 1f32)
+}
+
+/// Splits a double buffer into the half that is read on `frame_count` and the half that is
+/// written.
+pub fn split_dbl_buf<T>(bufs: &mut [T; 2], frame_count: usize) -> (&T, &mut T) {
+    let (first, second) = bufs.split_at_mut(1);
+    if frame_count % 2 == 0 {
+        (&first[0], &mut second[0])
+    } else {
+        (&second[0], &mut first[0])
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Parameters of a small grid for the backend tests
+    pub fn params(dim: usize, boundary: Boundary) -> SimulationParams {
+        SimulationParams {
+            dim,
+            fixed_dt: 1f32 / 60f32,
+            cell_dist: physics_cell_dist(dim),
+            radiate: 0.1f32,
+            diffuse: 0.1f32,
+            melt_temp: 1f32,
+            gravity_constant: 250f32,
+            boundary,
+            platform: String::new(),
+            device: String::new(),
+            marching_cubes: false,
+        }
+    }
+
+    /// Host-side buffers of one step
+    #[derive(Clone)]
+    pub struct HostFields {
+        pub mass: Vec<f32>,
+        pub flow: Vec<f32>,
+        pub temp: Vec<f32>,
+    }
+
+    impl HostFields {
+        /// A molten ball of mass in the middle of the grid, with the surface at a quarter of the
+        /// grid from the center
+        pub fn molten_ball(dim: usize) -> HostFields {
+            let center = (dim - 1) as f32 * 0.5f32;
+            let radius = dim as f32 * 0.25f32;
+            let mut mass = Vec::with_capacity(dim * dim * dim);
+            for z in 0..dim {
+                for y in 0..dim {
+                    for x in 0..dim {
+                        let d = Vector3f::new(x as f32, y as f32, z as f32)
+                            - Vector3f::new(center, center, center);
+                        mass.push(((d.magnitude() - radius) * 0.5f32).max(-1f32).min(1f32));
+                    }
+                }
+            }
+            let temp = mass.iter().map(|&m| if m < 0f32 { 2f32 } else { 0f32 }).collect();
+            HostFields {
+                mass,
+                flow: vec![0f32; dim * dim * dim * FLOW_STRIDE],
+                temp,
+            }
+        }
+
+        /// No mass anywhere and zero temperature
+        pub fn empty(dim: usize) -> HostFields {
+            HostFields {
+                mass: vec![1f32; dim * dim * dim],
+                flow: vec![0f32; dim * dim * dim * FLOW_STRIDE],
+                temp: vec![0f32; dim * dim * dim],
+            }
+        }
+
+        pub fn view(&self) -> Fields {
+            Fields {
+                mass: &self.mass,
+                flow: &self.flow,
+                temp: &self.temp,
+            }
+        }
+
+        pub fn view_mut(&mut self) -> FieldsMut {
+            FieldsMut {
+                mass: &mut self.mass,
+                flow: &mut self.flow,
+                temp: &mut self.temp,
+            }
+        }
+
+        /// Sum of the normalized mass of all cells
+        pub fn total_mass(&self) -> f64 {
+            self.mass.iter().map(|&m| normf(m) as f64).sum()
+        }
+
+        /// Sum of the temperatures of all cells
+        pub fn total_temp(&self) -> f64 {
+            self.temp.iter().map(|&t| t as f64).sum()
+        }
+    }
+
+    /// Runs `steps` fixed steps of the backend from frame 0 and downloads the result into the
+    /// half of `bufs` that is read next, `bufs[steps % 2]`.
+    pub fn run(backend: &mut SimulationBackend, bufs: &mut [HostFields; 2], steps: usize) {
+        for frame_count in 0..steps {
+            let (src, dst) = split_dbl_buf(bufs, frame_count);
+            backend.simulate(src.view(), dst.view_mut(), frame_count);
+        }
+        backend.download(bufs[steps % 2].view_mut(), steps);
+    }
+}
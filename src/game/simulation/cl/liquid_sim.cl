@@ -6,140 +6,491 @@
 // DT := fixed_deltatime
 // DX := distance between two cell-centers
 // DX3 := volume of a cell
+// RADIATE := temperature loss due to "radiation" per second
+// DIFFUSE := temperature diffusion coefficient between neighboring cells
+// MELT_TEMP := temperature at which solid matter melts and below which it refreezes
+// DIM := side length of the simulation grid
+// BLOCK := side length of a gravity block in cells
+// COARSE_DIM := side length of the coarse grid of gravity blocks
+// G := gravitational constant in cell units, see `cell_constant` in `gravity.rs`
+// BOUNDARY := how the border of the grid behaves, one of the BOUNDARY_* values below
+
+// Don't fuse multiplies and adds, so that the results match the CPU backend on all devices
+#pragma OPENCL FP_CONTRACT OFF
+
+// The border is kept empty, mass that flows into it is deleted and counted as ejected
+#define BOUNDARY_OPEN 0
+// The border mirrors the neighboring cells and no mass flows through it
+#define BOUNDARY_CLOSED 1
+// The grid wraps around, there is no border
+#define BOUNDARY_PERIODIC 2
 
 // Normalize the marching-cube related ranges from {-1, 1} where mass is -1 and
 // no-mass is 1 into {0, 1} where mass is 1 and 0 is no-mass
 #define NORMF(x) (((-(x)) + 1.0f) * 0.5f)
 // The same, but backwards
 #define DENORMF(x) (-((x) * 2.0f - 1.0f))
+// A cell is solid when it's inside the surface and colder than the melting point. Takes the
+// normalized mass.
+#define IS_SOLID(m, t) ((m) > 0.5f && (t) < MELT_TEMP)
+// Amount of gravitating matter in a cell: the depth of the cell below the surface. Takes the
+// raw mass.
+#define DENSITY(x) clamp(-(x), 0.0f, 1.0f)
+// Accelerations are clamped to this to keep the simulation stable
+#define MAX_ACCEL 10.0f
+// Border cells are handled by the boundary conditions; periodic grids have no border.
+#if BOUNDARY == BOUNDARY_PERIODIC
+#define IS_EDGE(dim, z, y, x) false
+#else
+#define IS_EDGE(dim, z, y, x) \
+    ((z) == 0 || (y) == 0 || (x) == 0 || (z) == (dim)-1 || (y) == (dim)-1 || (x) == (dim)-1)
+#endif
+// Whether a coordinate is inside the border along its axis
+#define INTERIOR(dim, c) ((c) > 0 && (c) < (dim)-1)
+// Indices of the negative neighbors, wrapping around for periodic boundaries
+#define NEIGHBOR_ZN(dim, z, y, x) (((z) + (dim) - 1) % (dim) * (dim) * (dim) + (y) * (dim) + (x))
+#define NEIGHBOR_YN(dim, z, y, x) ((z) * (dim) * (dim) + ((y) + (dim) - 1) % (dim) * (dim) + (x))
+#define NEIGHBOR_XN(dim, z, y, x) ((z) * (dim) * (dim) + (y) * (dim) + ((x) + (dim) - 1) % (dim))
+// Net flow out of a cell through its six faces: its own flows `p` leave towards the positive
+// neighbors and the flows of its negative neighbors along each axis enter. Summed in this order
+// on all backends.
+#define NET_OUTFLOW(p, zn, yn, xn) (((p).z - (zn)) + ((p).y - (yn)) + ((p).x - (xn)))
+// Sum of the flows that leave a cell, in the same order
+#define TOTAL_OUTFLOW(p, zn, yn, xn) \
+    (max((p).z, 0.0f) + max(-(zn), 0.0f) + \
+     max((p).y, 0.0f) + max(-(yn), 0.0f) + \
+     max((p).x, 0.0f) + max(-(xn), 0.0f))
+// A Magical Stabilization Factor (time-dependent < 1)
+#define F_STAB (1.0f*DT)
+// Whether a cell can take all of its inflow without holding more than one unit of mass. Takes
+// the normalized mass.
+#define HAS_ROOM(m, entering) ((m) + (entering) <= 1.0f)
+
+// Gravity pass 1: sums the mass into blocks of BLOCK^3 cells. Each block is stored as its
+// center of mass in cell units and its total mass. The border cells are skipped, as the boundary
+// conditions overwrite them. Run over the coarse grid.
+__kernel void coarsen_mass(
+        __global float const * restrict const mass,
+        __global float4 * restrict coarse) {
+    const size_t bz = get_global_id(0);
+    const size_t by = get_global_id(1);
+    const size_t bx = get_global_id(2);
+
+    float3 weighted = (float3)(0.0f, 0.0f, 0.0f);
+    float total = 0.0f;
+    for (size_t z = bz * BLOCK; z < min((bz + 1) * BLOCK, (size_t)DIM); ++z) {
+        for (size_t y = by * BLOCK; y < min((by + 1) * BLOCK, (size_t)DIM); ++y) {
+            for (size_t x = bx * BLOCK; x < min((bx + 1) * BLOCK, (size_t)DIM); ++x) {
+                if (IS_EDGE(DIM, z, y, x)) {
+                    continue;
+                }
+                const float m = DENSITY(mass[z * DIM * DIM + y * DIM + x]);
+                weighted += (float3)((float)x, (float)y, (float)z) * m;
+                total += m;
+            }
+        }
+    }
+    const float3 center = total > 0.0f ? weighted / total : (float3)(0.0f, 0.0f, 0.0f);
+    coarse[bz * COARSE_DIM * COARSE_DIM + by * COARSE_DIM + bx] = (float4)(center, total);
+}
+
+// Gravity pass 2: sums the pull of all blocks into a per-cell acceleration. The pull is softened
+// by one block so that the blocks close to the cell don't blow up. With periodic boundaries only
+// the nearest image of each block pulls.
+__kernel void compute_gravity(
+        __global float4 const * restrict const coarse,
+        __global float3 * restrict gravity) {
+    const size_t dim = get_global_size(0);
+    const size_t z = get_global_id(0);
+    const size_t y = get_global_id(1);
+    const size_t x = get_global_id(2);
+
+    const float3 pos = (float3)((float)x, (float)y, (float)z);
+    const float soft2 = (float)(BLOCK * BLOCK);
+    float3 accel = (float3)(0.0f, 0.0f, 0.0f);
+    for (size_t b = 0; b < COARSE_DIM * COARSE_DIM * COARSE_DIM; ++b) {
+        const float4 block = coarse[b];
+        if (block.w <= 0.0f) {
+            continue;
+        }
+        float3 diff = block.xyz - pos;
+#if BOUNDARY == BOUNDARY_PERIODIC
+        diff -= (float)DIM * round(diff / (float)DIM);
+#endif
+        const float d2 = dot(diff, diff) + soft2;
+        accel += diff * (G * block.w / (d2 * sqrt(d2)));
+    }
+    gravity[z * dim * dim + y * dim + x] = clamp(accel, -MAX_ACCEL, MAX_ACCEL);
+}
+
+// The liquid update runs in five passes. Each pass only reads buffers that the previous passes
+// have finished writing, the in-order queue acts as a barrier between them, so the results don't
+// depend on the order in which the work items run.
+
+// The flow from a cell towards its positive neighbor along one axis, increased along gravity by
+// the mass differential. Nothing flows through a face next to a solid cell. Takes the normalized
+// masses.
+float face_flow(
+        const float flow,
+        const float mass,
+        const float temp,
+        const float mass_p,
+        const float temp_p,
+        const float g) {
+    // Cross-section of the pipe
+    const float A = 0.0001f;
+
+    // Phase: solid cells hold their mass
+    if (IS_SOLID(mass, temp) || IS_SOLID(mass_p, temp_p)) {
+        return 0.0f;
+    }
+    return flow + A * (mass_p - mass) * g * DT / DX;
+}
+
+// Liquid pass 1: increases the flow of each cell towards its positive neighbors along gravity.
+// The flows are not limited yet.
+__kernel void compute_flow(
+        __global float const * restrict const old_mass,
+        __global float3 const * restrict const old_flow,
+        __global float const * restrict const old_temp,
+        __global float3 const * restrict const gravity,
+        __global float3 * restrict raw_flow) {
+    const size_t dim = get_global_size(0);
+    const size_t z = get_global_id(0);
+    const size_t y = get_global_id(1);
+    const size_t x = get_global_id(2);
+
+    const size_t gid = z * dim * dim + y * dim + x;
+
+    // Assign the positive neighbors, wrapping around for periodic boundaries
+    const size_t gid_zp = ((z + 1) % dim) * dim * dim + y * dim + x;
+    const size_t gid_yp = z * dim * dim + ((y + 1) % dim) * dim + x;
+    const size_t gid_xp = z * dim * dim + y * dim + (x + 1) % dim;
+
+    // Load neighborhood
+    const float mass = NORMF(old_mass[gid]);
+    const float mass_zp = NORMF(old_mass[gid_zp]);
+    const float mass_yp = NORMF(old_mass[gid_yp]);
+    const float mass_xp = NORMF(old_mass[gid_xp]);
+    const float temp = old_temp[gid];
+    const float3 flow = old_flow[gid];
+
+    // Gravity acceleration from the mass distribution, see compute_gravity
+    const float3 g_accel = gravity[gid];
+
+    float3 new_flow = (float3)(0.0f, 0.0f, 0.0f);
+    if (IS_EDGE(dim, z, y, x)) {
+#if BOUNDARY == BOUNDARY_OPEN
+        // Open borders take the mass of the interior cells next to them on all faces. The flow
+        // between a border cell on a low face and its interior neighbor belongs to the border
+        // cell; the border cells have no mass, so it's limited to flow out of the interior.
+        if (z == 0 && INTERIOR(dim, y) && INTERIOR(dim, x)) {
+            new_flow.z = face_flow(flow.z, mass, temp, mass_zp, old_temp[gid_zp], g_accel.z);
+        }
+        if (y == 0 && INTERIOR(dim, z) && INTERIOR(dim, x)) {
+            new_flow.y = face_flow(flow.y, mass, temp, mass_yp, old_temp[gid_yp], g_accel.y);
+        }
+        if (x == 0 && INTERIOR(dim, z) && INTERIOR(dim, y)) {
+            new_flow.x = face_flow(flow.x, mass, temp, mass_xp, old_temp[gid_xp], g_accel.x);
+        }
+#endif
+        raw_flow[gid] = new_flow;
+        return;
+    }
+
+    // Closed borders: nothing flows into them
+    if (BOUNDARY != BOUNDARY_CLOSED || z != dim-2) {
+        new_flow.z = face_flow(flow.z, mass, temp, mass_zp, old_temp[gid_zp], g_accel.z);
+    }
+    if (BOUNDARY != BOUNDARY_CLOSED || y != dim-2) {
+        new_flow.y = face_flow(flow.y, mass, temp, mass_yp, old_temp[gid_yp], g_accel.y);
+    }
+    if (BOUNDARY != BOUNDARY_CLOSED || x != dim-2) {
+        new_flow.x = face_flow(flow.x, mass, temp, mass_xp, old_temp[gid_xp], g_accel.x);
+    }
+    raw_flow[gid] = new_flow;
+}
+
+// Liquid pass 2: the factor that the flows leaving each cell are scaled with so that the cell
+// doesn't give more mass than it has. Inflows are limited by the cells that they leave here and by
+// the cells that they enter in limit_inflow.
+__kernel void limit_outflow(
+        __global float const * restrict const old_mass,
+        __global float3 const * restrict const raw_flow,
+        __global float * restrict limit) {
+    const size_t dim = get_global_size(0);
+    const size_t z = get_global_id(0);
+    const size_t y = get_global_id(1);
+    const size_t x = get_global_id(2);
+
+    const size_t gid = z * dim * dim + y * dim + x;
+
+    // Border cells are limited too, the empty cells of an open border don't give any mass
+    const float mass = NORMF(old_mass[gid]);
+    const float total_outflow = TOTAL_OUTFLOW(
+        raw_flow[gid],
+        raw_flow[NEIGHBOR_ZN(dim, z, y, x)].z,
+        raw_flow[NEIGHBOR_YN(dim, z, y, x)].y,
+        raw_flow[NEIGHBOR_XN(dim, z, y, x)].x);
+    const float moved = DT * total_outflow * F_STAB / DX3;
 
-__kernel void simulate_liquid(
+    // Limit mass-outflow in case there's not enough mass to go around
+    limit[gid] = moved > mass ? mass / moved : 1.0f;
+}
+
+// Liquid pass 3: the mass that enters each cell on this step, with the flows scaled by the limits
+// of the cells that they leave. Summed in the same order as TOTAL_OUTFLOW. The border cells take
+// any amount.
+__kernel void sum_inflow(
+        __global float3 const * restrict const raw_flow,
+        __global float const * restrict const limit,
+        __global float * restrict inflow) {
+    const size_t dim = get_global_size(0);
+    const size_t z = get_global_id(0);
+    const size_t y = get_global_id(1);
+    const size_t x = get_global_id(2);
+
+    const size_t gid = z * dim * dim + y * dim + x;
+    if (IS_EDGE(dim, z, y, x)) {
+        inflow[gid] = 0.0f;
+        return;
+    }
+
+    const size_t gid_zp = ((z + 1) % dim) * dim * dim + y * dim + x;
+    const size_t gid_zn = NEIGHBOR_ZN(dim, z, y, x);
+    const size_t gid_yp = z * dim * dim + ((y + 1) % dim) * dim + x;
+    const size_t gid_yn = NEIGHBOR_YN(dim, z, y, x);
+    const size_t gid_xp = z * dim * dim + y * dim + (x + 1) % dim;
+    const size_t gid_xn = NEIGHBOR_XN(dim, z, y, x);
+
+    const float3 p = raw_flow[gid];
+    const float total_inflow =
+        max(-p.z, 0.0f) * limit[gid_zp] + max(raw_flow[gid_zn].z, 0.0f) * limit[gid_zn] +
+        max(-p.y, 0.0f) * limit[gid_yp] + max(raw_flow[gid_yn].y, 0.0f) * limit[gid_yn] +
+        max(-p.x, 0.0f) * limit[gid_xp] + max(raw_flow[gid_xn].x, 0.0f) * limit[gid_xn];
+    inflow[gid] = DT * total_inflow * F_STAB / DX3;
+}
+
+// The outflow of a cell through a face if the cell on the other side has room for all of its
+// inflow, see HAS_ROOM. Such outflows are not limited by the inflow limit, they leave for sure.
+float sure_outflow(
+        __global float const * restrict const old_mass,
+        __global float const * restrict const inflow,
+        const float outflow,
+        const size_t other) {
+    return HAS_ROOM(NORMF(old_mass[other]), inflow[other]) ? max(outflow, 0.0f) : 0.0f;
+}
+
+// Liquid pass 4: the factor that the flows entering each cell are scaled with so that the cell
+// doesn't hold more than one unit of mass. Only the outflows that leave for sure make room, as
+// the other outflows may be limited by the cells that they enter.
+__kernel void limit_inflow(
+        __global float const * restrict const old_mass,
+        __global float3 const * restrict const raw_flow,
+        __global float const * restrict const limit,
+        __global float const * restrict const inflow,
+        __global float * restrict inflow_limit) {
+    const size_t dim = get_global_size(0);
+    const size_t z = get_global_id(0);
+    const size_t y = get_global_id(1);
+    const size_t x = get_global_id(2);
+
+    const size_t gid = z * dim * dim + y * dim + x;
+    const float mass = NORMF(old_mass[gid]);
+    const float entering = inflow[gid];
+    if (IS_EDGE(dim, z, y, x) || HAS_ROOM(mass, entering)) {
+        inflow_limit[gid] = 1.0f;
+        return;
+    }
+
+    const size_t gid_zp = ((z + 1) % dim) * dim * dim + y * dim + x;
+    const size_t gid_zn = NEIGHBOR_ZN(dim, z, y, x);
+    const size_t gid_yp = z * dim * dim + ((y + 1) % dim) * dim + x;
+    const size_t gid_yn = NEIGHBOR_YN(dim, z, y, x);
+    const size_t gid_xp = z * dim * dim + y * dim + (x + 1) % dim;
+    const size_t gid_xn = NEIGHBOR_XN(dim, z, y, x);
+
+    const float3 p = raw_flow[gid];
+    const float total_sure =
+        sure_outflow(old_mass, inflow, p.z, gid_zp) +
+        sure_outflow(old_mass, inflow, -raw_flow[gid_zn].z, gid_zn) +
+        sure_outflow(old_mass, inflow, p.y, gid_yp) +
+        sure_outflow(old_mass, inflow, -raw_flow[gid_yn].y, gid_yn) +
+        sure_outflow(old_mass, inflow, p.x, gid_xp) +
+        sure_outflow(old_mass, inflow, -raw_flow[gid_xn].x, gid_xn);
+    const float sure = DT * total_sure * limit[gid] * F_STAB / DX3;
+
+    // Limit mass-inflow in case there's too much mass. Without inflow the cell is already over
+    // full and can only drain.
+    inflow_limit[gid] =
+        entering > 0.0f ? clamp((1.0f - mass + sure) / entering, 0.0f, 1.0f) : 1.0f;
+}
+
+// A flow from cell `from` to cell `to`, scaled by the outflow limit of the cell that it leaves
+// and the inflow limit of the cell that it enters
+float limited(
+        __global float const * restrict const limit,
+        __global float const * restrict const inflow_limit,
+        const float flow,
+        const size_t from,
+        const size_t to) {
+    return flow > 0.0f
+        ? flow * limit[from] * inflow_limit[to]
+        : flow * limit[to] * inflow_limit[from];
+}
+
+// Liquid pass 5: limits the flows and moves the mass and the heat. The flow through a face is
+// limited by both of the cells that it connects.
+__kernel void apply_mass(
         __global float const * restrict const old_mass,
         __global float3 const * restrict const old_flow,
         __global float const * restrict const old_temp,
+        __global float3 const * restrict const raw_flow,
+        __global float const * restrict const limit,
+        __global float const * restrict const inflow_limit,
         __global float * restrict new_mass,
         __global float3 * restrict new_flow,
-        __global float * restrict new_temp) {
+        __global float * restrict new_temp,
+        __global float * restrict ejected) {
     const size_t dim = get_global_size(0);
     const size_t z = get_global_id(0);
     const size_t y = get_global_id(1);
     const size_t x = get_global_id(2);
 
     const size_t gid = z * dim * dim + y * dim + x;
+
+    // Assign all relative indices for the cell, wrapping around for periodic boundaries
+    // (p, n) := (positive, negative)
+    const size_t gid_zp = ((z + 1) % dim) * dim * dim + y * dim + x;
+    const size_t gid_zn = NEIGHBOR_ZN(dim, z, y, x);
+    const size_t gid_yp = z * dim * dim + ((y + 1) % dim) * dim + x;
+    const size_t gid_yn = NEIGHBOR_YN(dim, z, y, x);
+    const size_t gid_xp = z * dim * dim + y * dim + (x + 1) % dim;
+    const size_t gid_xn = NEIGHBOR_XN(dim, z, y, x);
+
+    // The limited flows through the six faces of the cell. The faces that wrap around a
+    // non-periodic grid have no flow.
+    const float3 raw = raw_flow[gid];
+    const float3 flow = (float3)(
+        limited(limit, inflow_limit, raw.x, gid, gid_xp),
+        limited(limit, inflow_limit, raw.y, gid, gid_yp),
+        limited(limit, inflow_limit, raw.z, gid, gid_zp));
+    const float flow_zn = limited(limit, inflow_limit, raw_flow[gid_zn].z, gid_zn, gid);
+    const float flow_yn = limited(limit, inflow_limit, raw_flow[gid_yn].y, gid_yn, gid);
+    const float flow_xn = limited(limit, inflow_limit, raw_flow[gid_xn].x, gid_xn, gid);
+    const float net_outflow = NET_OUTFLOW(flow, flow_zn, flow_yn, flow_xn);
+
     // Edge-cases
-    if (z == 0 || y == 0 || x == 0 || z == dim-1 || y == dim-1 || x == dim-1) {
-        new_mass[gid] = old_mass[gid];
-        new_flow[gid] = old_flow[gid];
-        new_temp[gid] = old_temp[gid];
+    if (IS_EDGE(dim, z, y, x)) {
+#if BOUNDARY == BOUNDARY_OPEN
+        // Count the mass that flows into the border on this step
+        ejected[gid] -= DT * net_outflow * F_STAB / DX3;
+
+        new_mass[gid] = DENORMF(0.0f);
+        new_flow[gid] = flow;
+        new_temp[gid] = 0.0f;
+#else
+        // Mirror the closest interior cell
+        const size_t mirror =
+            clamp(z, (size_t)1, dim-2) * dim * dim +
+            clamp(y, (size_t)1, dim-2) * dim +
+            clamp(x, (size_t)1, dim-2);
+        new_mass[gid] = old_mass[mirror];
+        new_flow[gid] = (float3)(0.0f, 0.0f, 0.0f);
+        new_temp[gid] = old_temp[mirror];
+#endif
+        return;
     }
-    else {
-        // Assign all relative indices for the cell
-        // (p, n) := (positive, negative)
-        const size_t gid_zp = gid + dim * dim;
-        const size_t gid_zn = gid - dim * dim;
-        const size_t gid_yp = gid + dim;
-        const size_t gid_yn = gid - dim;
-        const size_t gid_xp = gid + 1;
-        const size_t gid_xn = gid - 1;
-
-        // Cross-section of the pipe
-        const float A = 0.0001f;
-        // A Magical Stabilization Factor (time-dependent < 1)
-        const float F_STAB = 1.0f*DT;
-
-        // Temperature loss due to "radiation"
-        const float RADIATE = 0.1f;
-        // Temperature diffusion
-        const float DIFFUSE = 0.1f;
-
-
-        // Load neighborhood
-        const float mass = NORMF(old_mass[gid]);
-        const float mass_zp = NORMF(old_mass[gid_zp]);
-        const float mass_yp = NORMF(old_mass[gid_yp]);
-        const float mass_xp = NORMF(old_mass[gid_xp]);
-        /*
-        const float temp_zp = old_temp[gid_zp];
-        const float temp_yp = old_temp[gid_yp];
-        const float temp_xp = old_temp[gid_xp];
-        */
-        const float3 flow = old_flow[gid];
-
-        // Compute vector to global model center and gravity acceleration
-        const float center = (float)(dim-1)/2.0f;
-        const float diff_x = (float)center-x;
-        const float diff_y = (float)center-y;
-        const float diff_z = (float)center-z;
-        // to_origo is normalized: len == 1
-        const float3 to_origo = normalize((float3)(diff_x, diff_y, diff_z));
-        const float dist2 = diff_x*diff_x + diff_y*diff_y + diff_z*diff_z;
-        const float3 g_accel = min(to_origo / dist2, 10.0f);
-
-        // Flow update:
-        //  Calculate mass differential and increase flow towards origo.
-        const float mass_diff_zp = mass_zp - mass;
-        const float delta_flow_z = A * mass_diff_zp * g_accel.z * DT / DX;
-        new_flow[gid].z = flow.z + delta_flow_z;
-        const float mass_diff_yp = mass_yp - mass;
-        const float delta_flow_y = A * mass_diff_yp * g_accel.y * DT / DX;
-        new_flow[gid].y = flow.y + delta_flow_y;
-        const float mass_diff_xp = mass_xp - mass;
-        const float delta_flow_x = A * mass_diff_xp * g_accel.x * DT / DX;
-        new_flow[gid].x = flow.x + delta_flow_x;
-
-        // Mass update:
-        //  Load stored new flows for updating mass.
-        __global float3 *zn_flow = &new_flow[gid_zn];
-        __global float3 *yn_flow = &new_flow[gid_yn];
-        __global float3 *xn_flow = &new_flow[gid_xn];
-        __global float3 *p_flow = &new_flow[gid];
-        float total_outflow =
-            (*p_flow).z + (*zn_flow).z +
-            (*p_flow).y + (*yn_flow).y +
-            (*p_flow).x + (*xn_flow).x;
-        // Limit mass-outflow in case there's not enough mass to go around
-
-        if (total_outflow > mass) {
-            // Multiply such that the flow is not allowed to move more than what is left
-            const float scale = total_outflow / mass;
-            // Scale back each out-flow-component
-            (*zn_flow).z = ((*zn_flow).z) / scale;
-            (*yn_flow).y = ((*yn_flow).y) / scale;
-            (*xn_flow).x = ((*xn_flow).x) / scale;
-            (*p_flow).z = ((*p_flow).z) / scale;
-            (*p_flow).y = ((*p_flow).y) / scale;
-            (*p_flow).x = ((*p_flow).x) / scale;
-        }
-        // Limit mass-inflow in case there's too much mass
-        else if (-total_outflow + mass > 1.0f) {
-            // Multiply such that the mass on the next frame will not exceed 1
-            const float scale = total_outflow / (1.0f - mass);
-            // Scale back each out-flow-component
-            (*zn_flow).z = ((*zn_flow).z) / scale;
-            (*yn_flow).y = ((*yn_flow).y) / scale;
-            (*xn_flow).x = ((*xn_flow).x) / scale;
-            (*p_flow).z = ((*p_flow).z) / scale;
-            (*p_flow).y = ((*p_flow).y) / scale;
-            (*p_flow).x = ((*p_flow).x) / scale;
-        }
-        float final_outflow =
-            (*p_flow).z + (*zn_flow).z +
-            (*p_flow).y + (*yn_flow).y +
-            (*p_flow).x + (*xn_flow).x;
 
-        new_mass[gid] = DENORMF(mass - DT * final_outflow * F_STAB / DX3);
+    // Mass update
+    new_flow[gid] = flow;
+    const float mass = NORMF(old_mass[gid]);
+    new_mass[gid] = DENORMF(mass - DT * net_outflow * F_STAB / DX3);
+
+    // Temperature update: temperature flows with advection and diffusion and radiates away
+    const float RAD_FACTOR = 1.0f - RADIATE*DT;
+    const float temp = old_temp[gid];
+    const float temp_zp = old_temp[gid_zp];
+    const float temp_yp = old_temp[gid_yp];
+    const float temp_xp = old_temp[gid_xp];
+    const float temp_zn = old_temp[gid_zn];
+    const float temp_yn = old_temp[gid_yn];
+    const float temp_xn = old_temp[gid_xn];
+
+    // Advection: heat moves with the mass flow of the previous step. The flow of a cell
+    // points to its positive neighbors; the heat is taken from the upwind cell.
+    const float3 old = old_flow[gid];
+    const float3 old_zn = old_flow[gid_zn];
+    const float3 old_yn = old_flow[gid_yn];
+    const float3 old_xn = old_flow[gid_xn];
+    const float heat_out =
+        old.z * (old.z > 0.0f ? temp : temp_zp) +
+        old.y * (old.y > 0.0f ? temp : temp_yp) +
+        old.x * (old.x > 0.0f ? temp : temp_xp);
+    const float heat_in =
+        old_zn.z * (old_zn.z > 0.0f ? temp_zn : temp) +
+        old_yn.y * (old_yn.y > 0.0f ? temp_yn : temp) +
+        old_xn.x * (old_xn.x > 0.0f ? temp_xn : temp);
+    const float advection = (heat_out - heat_in) * F_STAB / DX3;
+
+    // Diffusion to all six neighbors
+    const float diffusion = (
+        (temp_zp - temp) + (temp_zn - temp) +
+        (temp_yp - temp) + (temp_yn - temp) +
+        (temp_xp - temp) + (temp_xn - temp)) * DIFFUSE;
 
-        /*
-        // Temperature update: temperature flows with advection and diffusion and radiates away
-        const float RAD_FACTOR = 1.0 - RADIATE*DT;
-        const float advection = total_outflow;
+    new_temp[gid] = (temp + DT * (diffusion - advection)) * RAD_FACTOR;
+}
 
-        const float diffusion = (
-            (temp_zp - old_temp[gid]) +
-            (temp_yp - old_temp[gid]) +
-            (temp_xp - old_temp[gid])) * DIFFUSE;
+// Sums the mass deleted by open boundaries in cell order in a single work item, like the CPU
+// backend, so that only one float is copied back. Only the border cells hold any, the interior
+// of each row is skipped.
+__kernel void sum_ejected(
+        __global float const * restrict const ejected,
+        __global float * restrict total) {
+    float sum = 0.0f;
+    for (size_t z = 0; z < DIM; ++z) {
+        for (size_t y = 0; y < DIM; ++y) {
+            const bool face = z == 0 || y == 0 || z == DIM - 1 || y == DIM - 1;
+            const size_t step = face ? 1 : DIM - 1;
+            for (size_t x = 0; x < DIM; x += step) {
+                sum += ejected[z * DIM * DIM + y * DIM + x];
+            }
+        }
+    }
+    total[0] = sum;
+}
 
-        new_temp[gid] = (old_temp[gid] - DT * (advection + diffusion)) * RAD_FACTOR;
-        */
+// Adds heat along a beam that starts at `origin` and points along the normalized `dir`, both in
+// cell units. The beam reaches `depth` cells from the origin and the heat falls off linearly from
+// the beam axis to `radius`. `power` is the heat added per second at the beam axis.
+__kernel void inject_heat(
+        __global float * restrict temp,
+        const float3 origin,
+        const float3 dir,
+        const float radius,
+        const float depth,
+        const float power) {
+    const size_t dim = get_global_size(0);
+    const size_t z = get_global_id(0);
+    const size_t y = get_global_id(1);
+    const size_t x = get_global_id(2);
+
+    // Edge-cells are overwritten by the boundary conditions, don't heat them
+    if (BOUNDARY != BOUNDARY_PERIODIC &&
+            (z == 0 || y == 0 || x == 0 || z == dim-1 || y == dim-1 || x == dim-1)) {
+        return;
+    }
+
+    const size_t gid = z * dim * dim + y * dim + x;
+    const float3 pos = (float3)((float)x, (float)y, (float)z);
+    // Only heat cells in front of the beam origin
+    const float t = dot(pos - origin, dir);
+    if (t < 0.0f || t > depth) {
+        return;
+    }
+    const float dist = length(pos - (origin + t * dir));
+    if (dist < radius) {
+        temp[gid] += power * DT * (1.0f - dist / radius);
     }
 }
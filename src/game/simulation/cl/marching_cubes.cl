@@ -0,0 +1,171 @@
+// Marching cubes over the raw mass, where the surface is at zero and the inside is negative.
+// The triangle table is built by `triangle_table` in `ocl_marching_cubes.rs`.
+
+// Passed in as compile flags:
+// DIM := side length of the simulation grid
+// SCAN_GROUP := work-group size of the prefix sum
+// TABLE_ROW := entries per case in the triangle table
+
+#define IDX(x, y, z) ((z) * DIM * DIM + (y) * DIM + (x))
+// Floats per vertex: position and normal
+#define VERTEX_FLOATS 6
+
+// Corners of each edge of a cube. The bits of a corner index are its x, y and z offsets.
+__constant uchar EDGES[12][2] = {
+    {0, 1}, {0, 2}, {0, 4}, {1, 3}, {1, 5}, {2, 3},
+    {2, 6}, {3, 7}, {4, 5}, {4, 6}, {5, 7}, {6, 7},
+};
+
+size_t cube_id(const size_t x, const size_t y, const size_t z) {
+    return z * (DIM - 1) * (DIM - 1) + y * (DIM - 1) + x;
+}
+
+// Index of the corner of the cube at (x, y, z) in the grid
+size_t corner_idx(const size_t x, const size_t y, const size_t z, const uint corner) {
+    return IDX(x + (corner & 1), y + ((corner >> 1) & 1), z + ((corner >> 2) & 1));
+}
+
+// Bit i is set when corner i of the cube at (x, y, z) is inside the surface
+uint cube_case(
+        __global float const * restrict const mass,
+        const size_t x,
+        const size_t y,
+        const size_t z) {
+    uint c = 0;
+    for (uint i = 0; i < 8; ++i) {
+        if (mass[corner_idx(x, y, z, i)] < 0.0f) {
+            c |= 1u << i;
+        }
+    }
+    return c;
+}
+
+// Number of triangles in the case
+uint triangle_count(__global char const * restrict const table, const uint c) {
+    uint n = 0;
+    while (n < TABLE_ROW && table[c * TABLE_ROW + n] >= 0) {
+        n += 3;
+    }
+    return n / 3;
+}
+
+// Gradient of the mass at a cell by central differences, one-sided at the border of the grid.
+// Points out of the surface.
+float3 gradient(
+        __global float const * restrict const mass,
+        const size_t x,
+        const size_t y,
+        const size_t z) {
+    const size_t x0 = x > 0 ? x - 1 : x;
+    const size_t y0 = y > 0 ? y - 1 : y;
+    const size_t z0 = z > 0 ? z - 1 : z;
+    const size_t x1 = min(x + 1, (size_t)(DIM - 1));
+    const size_t y1 = min(y + 1, (size_t)(DIM - 1));
+    const size_t z1 = min(z + 1, (size_t)(DIM - 1));
+    return (float3)(
+        (mass[IDX(x1, y, z)] - mass[IDX(x0, y, z)]) / (float)(x1 - x0),
+        (mass[IDX(x, y1, z)] - mass[IDX(x, y0, z)]) / (float)(y1 - y0),
+        (mass[IDX(x, y, z1)] - mass[IDX(x, y, z0)]) / (float)(z1 - z0));
+}
+
+// Pass 1: counts the triangles of each cube. Run over the (DIM - 1)^3 cubes.
+__kernel void classify_cubes(
+        __global float const * restrict const mass,
+        __global char const * restrict const table,
+        __global uint * restrict counts) {
+    const size_t z = get_global_id(0);
+    const size_t y = get_global_id(1);
+    const size_t x = get_global_id(2);
+
+    counts[cube_id(x, y, z)] = triangle_count(table, cube_case(mass, x, y, z));
+}
+
+// Pass 2a: exclusive prefix sum of the counts within each work-group of SCAN_GROUP items. The
+// sum of the group is stored for the next passes.
+__kernel void scan_groups(
+        __global uint const * restrict const counts,
+        __global uint * restrict offsets,
+        __global uint * restrict group_sums) {
+    const size_t gid = get_global_id(0);
+    const size_t lid = get_local_id(0);
+    __local uint sums[SCAN_GROUP];
+
+    const uint count = counts[gid];
+    sums[lid] = count;
+    barrier(CLK_LOCAL_MEM_FENCE);
+    for (size_t step = 1; step < SCAN_GROUP; step <<= 1) {
+        const uint add = lid >= step ? sums[lid - step] : 0;
+        barrier(CLK_LOCAL_MEM_FENCE);
+        sums[lid] += add;
+        barrier(CLK_LOCAL_MEM_FENCE);
+    }
+    offsets[gid] = sums[lid] - count;
+    if (lid == SCAN_GROUP - 1) {
+        group_sums[get_group_id(0)] = sums[lid];
+    }
+}
+
+// Pass 2b: exclusive prefix sum of the group sums in a single work item. The total number of
+// triangles is stored after the groups.
+__kernel void scan_group_sums(
+        __global uint * restrict group_sums,
+        const uint groups) {
+    uint total = 0;
+    for (uint i = 0; i < groups; ++i) {
+        const uint sum = group_sums[i];
+        group_sums[i] = total;
+        total += sum;
+    }
+    group_sums[groups] = total;
+}
+
+// Pass 2c: offsets the items of each group by the sum of the previous groups.
+__kernel void add_group_offsets(
+        __global uint * restrict offsets,
+        __global uint const * restrict const group_sums) {
+    const size_t gid = get_global_id(0);
+    offsets[gid] += group_sums[gid / SCAN_GROUP];
+}
+
+// Pass 3: writes the triangles of each cube at its offset, three vertices per triangle. The
+// positions are in the [0, 1] sample space and the normals point out of the surface.
+__kernel void generate_triangles(
+        __global float const * restrict const mass,
+        __global char const * restrict const table,
+        __global uint const * restrict const offsets,
+        __global float * restrict vertices) {
+    const size_t z = get_global_id(0);
+    const size_t y = get_global_id(1);
+    const size_t x = get_global_id(2);
+
+    const uint c = cube_case(mass, x, y, z);
+    if (c == 0 || c == 255) {
+        return;
+    }
+
+    float values[8];
+    float3 normals[8];
+    float3 corners[8];
+    for (uint i = 0; i < 8; ++i) {
+        const size_t cx = x + (i & 1);
+        const size_t cy = y + ((i >> 1) & 1);
+        const size_t cz = z + ((i >> 2) & 1);
+        values[i] = mass[IDX(cx, cy, cz)];
+        normals[i] = gradient(mass, cx, cy, cz);
+        corners[i] = (float3)((float)cx, (float)cy, (float)cz);
+    }
+
+    __global float *out = vertices + offsets[cube_id(x, y, z)] * 3 * VERTEX_FLOATS;
+    for (uint n = 0; n < TABLE_ROW && table[c * TABLE_ROW + n] >= 0; ++n) {
+        const uint edge = table[c * TABLE_ROW + n];
+        const uint a = EDGES[edge][0];
+        const uint b = EDGES[edge][1];
+        // The corners are on different sides of the surface, so the values differ
+        const float t = values[a] / (values[a] - values[b]);
+        const float3 pos = mix(corners[a], corners[b], t) / (float)(DIM - 1);
+        const float3 normal = mix(normals[a], normals[b], t);
+        const float len = length(normal);
+        vstore3(pos, 0, out + n * VERTEX_FLOATS);
+        vstore3(len > 0.0f ? normal / len : normal, 1, out + n * VERTEX_FLOATS);
+    }
+}
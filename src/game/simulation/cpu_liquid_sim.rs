@@ -0,0 +1,587 @@
+use rayon::prelude::*;
+use prelude::*;
+use super::backend::*;
+use super::gravity;
+
+/// Cross-section of the pipe
+const A: f32 = 0.0001f32;
+
+/// A multi-threaded port of `cl/liquid_sim.cl` for systems without OpenCL, and the reference that
+/// the kernels are checked against. Runs one z-slice per task.
+pub struct CpuLiquidSim {
+    params: SimulationParams,
+    // Blocks of the coarse grid, four floats each
+    coarse: Vec<f32>,
+    // Per-cell gravity acceleration as float3's
+    gravity: Vec<f32>,
+    // Flows before limiting, as float3's
+    raw_flow: Vec<f32>,
+    // Per-cell factor that the flows leaving the cell are limited with
+    limit: Vec<f32>,
+    // Mass that enters each cell on this step, after the outflow limits
+    inflow: Vec<f32>,
+    // Per-cell factor that the flows entering the cell are limited with
+    inflow_limit: Vec<f32>,
+    // Mass deleted by open boundaries, accumulated per border cell like on the device
+    ejected: Vec<f32>,
+}
+
+impl CpuLiquidSim {
+    pub fn new(params: &SimulationParams) -> CpuLiquidSim {
+        let dim = params.dim;
+        let (_, coarse_dim) = gravity::blocks(dim);
+        CpuLiquidSim {
+            params: params.clone(),
+            coarse: vec![0f32; coarse_dim * coarse_dim * coarse_dim * 4],
+            gravity: vec![0f32; dim * dim * dim * FLOW_STRIDE],
+            raw_flow: vec![0f32; dim * dim * dim * FLOW_STRIDE],
+            limit: vec![0f32; dim * dim * dim],
+            inflow: vec![0f32; dim * dim * dim],
+            inflow_limit: vec![0f32; dim * dim * dim],
+            ejected: vec![0f32; dim * dim * dim],
+        }
+    }
+}
+
+impl SimulationBackend for CpuLiquidSim {
+    fn name(&self) -> &'static str {
+        "CPU"
+    }
+
+    fn simulate(&mut self, src: Fields, dst: FieldsMut, _: usize) {
+        let p = &self.params;
+        let dim = p.dim;
+        let slice_len = dim * dim;
+
+        // Gravity pass over the mass that is read on this step
+        gravity::coarsen_mass(src.mass, dim, p.boundary, &mut self.coarse);
+        let g = gravity::cell_constant(p.gravity_constant, dim);
+        gravity::compute_gravity(&self.coarse, dim, p.boundary, g, &mut self.gravity);
+        let g_field: &[f32] = &self.gravity;
+
+        // The update runs in the same five passes as the kernels: compute the flows, limit the
+        // outflows, sum and limit the inflows, then move the mass. Each pass only reads what the
+        // previous passes wrote.
+
+        // Flow update: calculate mass differential and increase flow along gravity.
+        self.raw_flow
+            .par_chunks_mut(slice_len * FLOW_STRIDE)
+            .enumerate()
+            .for_each(|(z, flow_slice)| {
+                for y in 0..dim {
+                    for x in 0..dim {
+                        let gid = z * slice_len + y * dim + x;
+                        let local = (y * dim + x) * FLOW_STRIDE;
+                        let old = &src.flow[gid * FLOW_STRIDE..gid * FLOW_STRIDE + 3];
+                        let new = &mut flow_slice[local..local + 3];
+                        new.copy_from_slice(&[0f32; 3]);
+
+                        let nb = Neighbors::new(dim, z, y, x);
+                        let coords = [x, y, z];
+                        let mass = normf(src.mass[gid]);
+                        let temp = src.temp[gid];
+                        let g = &g_field[gid * FLOW_STRIDE..gid * FLOW_STRIDE + 3];
+                        // Flow components are stored as (x, y, z)
+                        let flow_to_p = |c: usize| {
+                            let mass_p = normf(src.mass[nb.p[c]]);
+                            face_flow(p, old[c], mass, temp, mass_p, src.temp[nb.p[c]], g[c])
+                        };
+
+                        if is_edge(p, z, y, x) {
+                            if p.boundary == Boundary::Open {
+                                // Open borders take the mass of the interior cells next to them
+                                // on all faces. The flow between a border cell on a low face and
+                                // its interior neighbor belongs to the border cell; the border
+                                // cells have no mass, so it's limited to flow out of the
+                                // interior.
+                                for c in 0..3 {
+                                    let on_low_face = coords[c] == 0
+                                        && (0..3).all(|o| o == c || is_interior(dim, coords[o]));
+                                    if on_low_face {
+                                        new[c] = flow_to_p(c);
+                                    }
+                                }
+                            }
+                            continue;
+                        }
+
+                        for c in 0..3 {
+                            // Closed borders: nothing flows into them
+                            if p.boundary != Boundary::Closed || coords[c] != dim - 2 {
+                                new[c] = flow_to_p(c);
+                            }
+                        }
+                    }
+                }
+            });
+
+        let dx3 = p.cell_dist * p.cell_dist * p.cell_dist;
+        // A Magical Stabilization Factor (time-dependent < 1)
+        let f_stab = 1f32 * p.fixed_dt;
+
+        // Limit the flows leaving each cell so that it doesn't give more mass than it has.
+        // Inflows are limited by the cells that they leave here and by the cells that they enter
+        // below.
+        let raw_flow: &[f32] = &self.raw_flow;
+        self.limit
+            .par_chunks_mut(slice_len)
+            .enumerate()
+            .for_each(|(z, limit_slice)| {
+                for y in 0..dim {
+                    for x in 0..dim {
+                        let gid = z * slice_len + y * dim + x;
+                        let local = y * dim + x;
+                        // Border cells are limited too, the empty cells of an open border don't
+                        // give any mass
+                        let nb = Neighbors::new(dim, z, y, x);
+                        let flow = |idx: usize, c: usize| raw_flow[idx * FLOW_STRIDE + c];
+                        let mass = normf(src.mass[gid]);
+                        let total_outflow = total_outflow(
+                            [flow(gid, 0), flow(gid, 1), flow(gid, 2)],
+                            [flow(nb.n[0], 0), flow(nb.n[1], 1), flow(nb.n[2], 2)],
+                        );
+
+                        let moved = p.fixed_dt * total_outflow * f_stab / dx3;
+
+                        // Limit mass-outflow in case there's not enough mass to go around
+                        limit_slice[local] = if moved > mass { mass / moved } else { 1f32 };
+                    }
+                }
+            });
+
+        // The mass that enters each cell, with the flows scaled by the limits of the cells that
+        // they leave. The border cells take any amount.
+        let limit: &[f32] = &self.limit;
+        self.inflow
+            .par_chunks_mut(slice_len)
+            .enumerate()
+            .for_each(|(z, inflow_slice)| {
+                for y in 0..dim {
+                    for x in 0..dim {
+                        let gid = z * slice_len + y * dim + x;
+                        let local = y * dim + x;
+                        if is_edge(p, z, y, x) {
+                            inflow_slice[local] = 0f32;
+                            continue;
+                        }
+                        let nb = Neighbors::new(dim, z, y, x);
+                        let flow = |idx: usize, c: usize| raw_flow[idx * FLOW_STRIDE + c];
+                        // Same order as in the kernel: z, y, x
+                        let mut total_inflow = 0f32;
+                        for &c in [2, 1, 0].iter() {
+                            total_inflow = total_inflow
+                                + (-flow(gid, c)).max(0f32) * limit[nb.p[c]]
+                                + flow(nb.n[c], c).max(0f32) * limit[nb.n[c]];
+                        }
+                        inflow_slice[local] = p.fixed_dt * total_inflow * f_stab / dx3;
+                    }
+                }
+            });
+
+        // Limit the flows entering each cell so that it doesn't hold more than one unit of
+        // mass. Only the outflows that leave for sure make room, as the other outflows may be
+        // limited by the cells that they enter.
+        let inflow: &[f32] = &self.inflow;
+        self.inflow_limit
+            .par_chunks_mut(slice_len)
+            .enumerate()
+            .for_each(|(z, limit_slice)| {
+                for y in 0..dim {
+                    for x in 0..dim {
+                        let gid = z * slice_len + y * dim + x;
+                        let local = y * dim + x;
+                        let mass = normf(src.mass[gid]);
+                        let entering = inflow[gid];
+                        if is_edge(p, z, y, x) || has_room(mass, entering) {
+                            limit_slice[local] = 1f32;
+                            continue;
+                        }
+
+                        let nb = Neighbors::new(dim, z, y, x);
+                        let flow = |idx: usize, c: usize| raw_flow[idx * FLOW_STRIDE + c];
+                        // Outflows into cells that take all of their inflow leave for sure
+                        let sure_outflow = |outflow: f32, other: usize| {
+                            if has_room(normf(src.mass[other]), inflow[other]) {
+                                outflow.max(0f32)
+                            } else {
+                                0f32
+                            }
+                        };
+                        // Same order as in the kernel: z, y, x
+                        let mut total_sure = 0f32;
+                        for &c in [2, 1, 0].iter() {
+                            total_sure = total_sure + sure_outflow(flow(gid, c), nb.p[c])
+                                + sure_outflow(-flow(nb.n[c], c), nb.n[c]);
+                        }
+                        let sure = p.fixed_dt * total_sure * limit[gid] * f_stab / dx3;
+
+                        // Limit mass-inflow in case there's too much mass. Without inflow the
+                        // cell is already over full and can only drain.
+                        limit_slice[local] = if entering > 0f32 {
+                            ((1f32 - mass + sure) / entering).max(0f32).min(1f32)
+                        } else {
+                            1f32
+                        };
+                    }
+                }
+            });
+
+        // Mass update: the flow through a face is limited by both of the cells that it connects
+        let inflow_limit: &[f32] = &self.inflow_limit;
+        dst.mass
+            .par_chunks_mut(slice_len)
+            .zip(dst.flow.par_chunks_mut(slice_len * FLOW_STRIDE))
+            .zip(dst.temp.par_chunks_mut(slice_len))
+            .zip(self.ejected.par_chunks_mut(slice_len))
+            .enumerate()
+            .for_each(|(z, (((mass_slice, flow_slice), temp_slice), ejected_slice))| {
+                for y in 0..dim {
+                    for x in 0..dim {
+                        let gid = z * slice_len + y * dim + x;
+                        let local = y * dim + x;
+                        let new_flow =
+                            &mut flow_slice[local * FLOW_STRIDE..local * FLOW_STRIDE + 3];
+                        let nb = Neighbors::new(dim, z, y, x);
+
+                        // The limited flows through the six faces of the cell. The faces that
+                        // wrap around a non-periodic grid have no flow.
+                        let flow = |idx: usize, c: usize| raw_flow[idx * FLOW_STRIDE + c];
+                        let mut flow_p = [0f32; 3];
+                        let mut flow_n = [0f32; 3];
+                        for c in 0..3 {
+                            flow_p[c] = limited(limit, inflow_limit, flow(gid, c), gid, nb.p[c]);
+                            flow_n[c] =
+                                limited(limit, inflow_limit, flow(nb.n[c], c), nb.n[c], gid);
+                        }
+                        let net_outflow = net_outflow(flow_p, flow_n);
+
+                        if is_edge(p, z, y, x) {
+                            match p.boundary {
+                                Boundary::Open => {
+                                    // Count the mass that flows into the border on this step
+                                    ejected_slice[local] -=
+                                        p.fixed_dt * net_outflow * f_stab / dx3;
+
+                                    mass_slice[local] = denormf(0f32);
+                                    new_flow.copy_from_slice(&flow_p);
+                                    temp_slice[local] = 0f32;
+                                }
+                                _ => {
+                                    // Mirror the closest interior cell
+                                    let interior = |c: usize| c.max(1).min(dim - 2);
+                                    let mirror =
+                                        interior(z) * slice_len + interior(y) * dim + interior(x);
+                                    mass_slice[local] = src.mass[mirror];
+                                    new_flow.copy_from_slice(&[0f32; 3]);
+                                    temp_slice[local] = src.temp[mirror];
+                                }
+                            }
+                            continue;
+                        }
+
+                        // Mass update
+                        new_flow.copy_from_slice(&flow_p);
+                        let mass = normf(src.mass[gid]);
+                        mass_slice[local] =
+                            denormf(mass - p.fixed_dt * net_outflow * f_stab / dx3);
+
+                        temp_slice[local] = next_temperature(p, &src, gid, &nb, f_stab, dx3);
+                    }
+                }
+            });
+    }
+
+    fn ejected_mass(&mut self) -> (f32, usize) {
+        // Summed serially in cell order, like on the device, so that the result doesn't depend
+        // on the scheduling of the threads
+        (self.ejected.iter().sum(), 0)
+    }
+
+    fn upload(&mut self, _: Fields, _: usize) {
+        // Works directly on the host-side buffers
+    }
+
+    fn inject_heat(&mut self, temp: &mut [f32], beam: &HeatBeam, _: usize) {
+        let dim = self.params.dim;
+        let dt = self.params.fixed_dt;
+        temp.par_chunks_mut(dim * dim)
+            .enumerate()
+            .for_each(|(z, temp_slice)| {
+                for y in 0..dim {
+                    for x in 0..dim {
+                        // Edge-cells are overwritten by the boundary conditions, don't heat them
+                        if is_edge(&self.params, z, y, x) {
+                            continue;
+                        }
+                        let pos = Vector3f::new(x as f32, y as f32, z as f32);
+                        temp_slice[y * dim + x] += beam.heat_at(pos) * dt;
+                    }
+                }
+            });
+    }
+}
+
+/// Indices of the six neighbors of a cell, wrapping around the grid for periodic boundaries.
+/// Indexed by axis in the same (x, y, z) order as the flow components.
+struct Neighbors {
+    /// Positive neighbors
+    p: [usize; 3],
+    /// Negative neighbors
+    n: [usize; 3],
+}
+
+impl Neighbors {
+    fn new(dim: usize, z: usize, y: usize, x: usize) -> Neighbors {
+        let idx = |z: usize, y: usize, x: usize| z * dim * dim + y * dim + x;
+        let inc = |c: usize| (c + 1) % dim;
+        let dec = |c: usize| (c + dim - 1) % dim;
+        Neighbors {
+            p: [idx(z, y, inc(x)), idx(z, inc(y), x), idx(inc(z), y, x)],
+            n: [idx(z, y, dec(x)), idx(z, dec(y), x), idx(dec(z), y, x)],
+        }
+    }
+}
+
+/// Temperature update: temperature flows with advection and diffusion and radiates away.
+fn next_temperature(
+    p: &SimulationParams,
+    src: &Fields,
+    gid: usize,
+    nb: &Neighbors,
+    f_stab: f32,
+    dx3: f32,
+) -> f32 {
+    let temp = src.temp[gid];
+    let old_flow = |idx: usize, c: usize| src.flow[idx * FLOW_STRIDE + c];
+
+    let mut heat_out = 0f32;
+    let mut heat_in = 0f32;
+    let mut diffusion = 0f32;
+    // Same order as in the kernel: z, y, x
+    for &c in [2, 1, 0].iter() {
+        let temp_p = src.temp[nb.p[c]];
+        let temp_n = src.temp[nb.n[c]];
+
+        // Advection: heat moves with the mass flow of the previous step. The flow of a cell
+        // points to its positive neighbors; the heat is taken from the upwind cell.
+        let flow_p = old_flow(gid, c);
+        let flow_n = old_flow(nb.n[c], c);
+        heat_out += flow_p * if flow_p > 0f32 { temp } else { temp_p };
+        heat_in += flow_n * if flow_n > 0f32 { temp_n } else { temp };
+
+        // Diffusion to all six neighbors
+        diffusion += (temp_p - temp) + (temp_n - temp);
+    }
+    let advection = (heat_out - heat_in) * f_stab / dx3;
+    let diffusion = diffusion * p.diffuse;
+    let rad_factor = 1f32 - p.radiate * p.fixed_dt;
+
+    (temp + p.fixed_dt * (diffusion - advection)) * rad_factor
+}
+
+/// Net flow out of a cell through its six faces: its own flows `p` leave towards the positive
+/// neighbors and the flows of its negative neighbors `n` enter, in (x, y, z) order. Summed in the
+/// same order as in the kernel.
+fn net_outflow(p: [f32; 3], n: [f32; 3]) -> f32 {
+    (p[2] - n[2]) + (p[1] - n[1]) + (p[0] - n[0])
+}
+
+/// Sum of the flows that leave a cell, in the same order as `net_outflow`
+fn total_outflow(p: [f32; 3], n: [f32; 3]) -> f32 {
+    p[2].max(0f32) + (-n[2]).max(0f32) + p[1].max(0f32) + (-n[1]).max(0f32) + p[0].max(0f32)
+        + (-n[0]).max(0f32)
+}
+
+/// A flow from cell `from` to cell `to`, scaled by the outflow limit of the cell that it leaves
+/// and the inflow limit of the cell that it enters
+fn limited(limit: &[f32], inflow_limit: &[f32], flow: f32, from: usize, to: usize) -> f32 {
+    if flow > 0f32 {
+        flow * limit[from] * inflow_limit[to]
+    } else {
+        flow * limit[to] * inflow_limit[from]
+    }
+}
+
+/// Whether a cell can take all of its inflow without holding more than one unit of mass. Takes
+/// the normalized mass.
+fn has_room(mass: f32, entering: f32) -> bool {
+    mass + entering <= 1f32
+}
+
+/// The flow from a cell towards its positive neighbor along one axis, increased along gravity by
+/// the mass differential. Nothing flows through a face next to a solid cell. Takes the normalized
+/// masses.
+fn face_flow(
+    p: &SimulationParams,
+    flow: f32,
+    mass: f32,
+    temp: f32,
+    mass_p: f32,
+    temp_p: f32,
+    g: f32,
+) -> f32 {
+    // Phase: solid cells hold their mass
+    if is_solid(p, mass, temp) || is_solid(p, mass_p, temp_p) {
+        return 0f32;
+    }
+    flow + A * (mass_p - mass) * g * p.fixed_dt / p.cell_dist
+}
+
+/// A cell is solid when it's inside the surface and colder than the melting point. Takes the
+/// normalized mass.
+fn is_solid(p: &SimulationParams, mass: f32, temp: f32) -> bool {
+    mass > 0.5f32 && temp < p.melt_temp
+}
+
+/// Whether a coordinate is inside the border along its axis
+fn is_interior(dim: usize, c: usize) -> bool {
+    c > 0 && c < dim - 1
+}
+
+/// Border cells are handled by the boundary conditions; periodic grids have no border.
+fn is_edge(p: &SimulationParams, z: usize, y: usize, x: usize) -> bool {
+    let dim = p.dim;
+    p.boundary != Boundary::Periodic
+        && (z == 0 || y == 0 || x == 0 || z == dim - 1 || y == dim - 1 || x == dim - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::backend::tests::*;
+
+    const DIM: usize = 16;
+
+    #[test]
+    fn hot_cell_diffuses_into_neighbors() {
+        let p = params(DIM, Boundary::Closed);
+        let center = DIM / 2;
+        let idx = |z: usize, y: usize, x: usize| z * DIM * DIM + y * DIM + x;
+        let mut bufs = [HostFields::empty(DIM), HostFields::empty(DIM)];
+        bufs[0].temp[idx(center, center, center)] = 1f32;
+        run(&mut CpuLiquidSim::new(&p), &mut bufs, 1);
+
+        // Each of the six neighbors gets its share of the difference, the cell loses it. All of
+        // them radiate.
+        let rad_factor = 1f32 - p.radiate * p.fixed_dt;
+        let share = p.fixed_dt * p.diffuse * rad_factor;
+        let temp = &bufs[1].temp;
+        for &(z, y, x) in &[
+            (center + 1, center, center),
+            (center - 1, center, center),
+            (center, center + 1, center),
+            (center, center - 1, center),
+            (center, center, center + 1),
+            (center, center, center - 1),
+        ] {
+            assert!((temp[idx(z, y, x)] - share).abs() < 1e-6);
+        }
+        assert!((temp[idx(center, center, center)] - (rad_factor - 6f32 * share)).abs() < 1e-6);
+        assert_eq!(temp[idx(center + 2, center, center)], 0f32);
+        assert_eq!(temp[idx(center + 1, center + 1, center)], 0f32);
+    }
+
+    #[test]
+    fn heat_radiates_away() {
+        // Without mass nothing flows and diffusion only moves the heat around the periodic grid
+        let p = params(DIM, Boundary::Periodic);
+        let mut bufs = [HostFields::empty(DIM), HostFields::empty(DIM)];
+        for (i, temp) in bufs[0].temp.iter_mut().enumerate() {
+            *temp = (i % 7) as f32;
+        }
+        let before = bufs[0].total_temp();
+        let steps = 10;
+        run(&mut CpuLiquidSim::new(&p), &mut bufs, steps);
+
+        let expected = before * (1f64 - (p.radiate * p.fixed_dt) as f64).powi(steps as i32);
+        let after = bufs[steps % 2].total_temp();
+        assert!(
+            (after - expected).abs() < 1e-4 * expected,
+            "{} after {} steps, expected {}",
+            after,
+            steps,
+            expected
+        );
+    }
+
+    #[test]
+    fn beam_adds_its_power_over_the_falloff() {
+        let p = params(DIM, Boundary::Closed);
+        let beam = HeatBeam {
+            origin: Vector3f::new(2f32, 7.3f32, 8.1f32),
+            dir: Vector3f::unit_x(),
+            radius: 3f32,
+            depth: 10f32,
+            power: 5f32,
+        };
+        let mut temp = vec![0f32; DIM * DIM * DIM];
+        CpuLiquidSim::new(&p).inject_heat(&mut temp, &beam, 0);
+
+        // The beam runs along x, so each cell is `t` cells along the beam and `dist` from it
+        let mut falloff = 0f64;
+        for z in 1..DIM - 1 {
+            for y in 1..DIM - 1 {
+                for x in 1..DIM - 1 {
+                    let t = x as f32 - beam.origin.x;
+                    let dy = y as f32 - beam.origin.y;
+                    let dz = z as f32 - beam.origin.z;
+                    let dist = (dy * dy + dz * dz).sqrt();
+                    if t >= 0f32 && t <= beam.depth && dist < beam.radius {
+                        falloff += (1f32 - dist / beam.radius) as f64;
+                    }
+                }
+            }
+        }
+        let expected = (beam.power * p.fixed_dt) as f64 * falloff;
+        let total: f64 = temp.iter().map(|&t| t as f64).sum();
+        assert!(falloff > 0f64);
+        assert!((total - expected).abs() < 1e-4 * expected, "{} != {}", total, expected);
+    }
+
+    #[test]
+    fn mass_is_conserved() {
+        for &boundary in &[Boundary::Open, Boundary::Periodic] {
+            let mut bufs = [HostFields::molten_ball(DIM), HostFields::molten_ball(DIM)];
+            let before = bufs[0].total_mass();
+            let mut sim = CpuLiquidSim::new(&params(DIM, boundary));
+            run(&mut sim, &mut bufs, 50);
+
+            let after = bufs[0].total_mass() + sim.ejected_mass().0 as f64;
+            assert!(
+                (after - before).abs() < 1e-3,
+                "{:?}: {} before, {} after",
+                boundary,
+                before,
+                after
+            );
+        }
+    }
+
+    #[test]
+    fn inflow_is_limited_to_a_full_cell() {
+        let p = params(DIM, Boundary::Closed);
+        let mut fields = HostFields::molten_ball(DIM);
+        // Nearly full everywhere, with all six neighbors of the center flowing into it. Without
+        // the inflow limit the center would end up with about 1.4 units of mass.
+        for m in fields.mass.iter_mut() {
+            *m = denormf(0.9f32);
+        }
+        let c = DIM / 2;
+        let idx = |z: usize, y: usize, x: usize| z * DIM * DIM + y * DIM + x;
+        let center = idx(c, c, c);
+        let negative = [idx(c, c, c - 1), idx(c, c - 1, c), idx(c - 1, c, c)];
+        for axis in 0..3 {
+            fields.flow[center * FLOW_STRIDE + axis] = -0.11f32;
+            fields.flow[negative[axis] * FLOW_STRIDE + axis] = 0.11f32;
+        }
+        let mut bufs = [fields.clone(), fields];
+        let before = bufs[0].total_mass();
+        let mut sim = CpuLiquidSim::new(&p);
+        run(&mut sim, &mut bufs, 1);
+
+        let max = bufs[1].mass.iter().map(|&m| normf(m)).fold(0f32, f32::max);
+        assert!(max <= 1f32 + 1e-5, "a cell holds {}", max);
+        assert!(normf(bufs[1].mass[center]) > 0.99f32);
+        assert!((bufs[1].total_mass() - before).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,352 @@
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use super::backend::*;
+
+/// File formats for the extracted surface
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MeshFormat {
+    Obj,
+    /// ASCII PLY with normals
+    Ply,
+    /// Binary STL with per-face normals
+    Stl,
+}
+
+impl MeshFormat {
+    pub fn from_name(name: &str) -> MeshFormat {
+        match name.to_lowercase().as_str() {
+            "obj" => MeshFormat::Obj,
+            "ply" => MeshFormat::Ply,
+            "stl" => MeshFormat::Stl,
+            other => {
+                warn!("unknown mesh format \"{}\", using OBJ", other);
+                MeshFormat::Obj
+            }
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            MeshFormat::Obj => "obj",
+            MeshFormat::Ply => "ply",
+            MeshFormat::Stl => "stl",
+        }
+    }
+}
+
+/// Writes an indexed triangle mesh as a Wavefront OBJ. `vertices` are interleaved positions and
+/// normals, 6 floats per vertex.
+pub fn write_obj<W: Write>(w: &mut W, vertices: &[f32], indices: &[u32]) -> io::Result<()> {
+    writeln!(w, "# Planetary destruction simulator surface")?;
+    for v in vertices.chunks(6) {
+        writeln!(w, "v {} {} {}", v[0], v[1], v[2])?;
+    }
+    for v in vertices.chunks(6) {
+        writeln!(w, "vn {} {} {}", v[3], v[4], v[5])?;
+    }
+    // OBJ indices start from 1
+    for tri in indices.chunks(3) {
+        writeln!(
+            w,
+            "f {0}//{0} {1}//{1} {2}//{2}",
+            tri[0] + 1,
+            tri[1] + 1,
+            tri[2] + 1
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes an indexed triangle mesh as an ASCII PLY with vertex normals. `vertices` are
+/// interleaved positions and normals, 6 floats per vertex.
+pub fn write_ply<W: Write>(w: &mut W, vertices: &[f32], indices: &[u32]) -> io::Result<()> {
+    writeln!(w, "ply")?;
+    writeln!(w, "format ascii 1.0")?;
+    writeln!(w, "comment Planetary destruction simulator surface")?;
+    writeln!(w, "element vertex {}", vertices.len() / 6)?;
+    for prop in &["x", "y", "z", "nx", "ny", "nz"] {
+        writeln!(w, "property float {}", prop)?;
+    }
+    writeln!(w, "element face {}", indices.len() / 3)?;
+    writeln!(w, "property list uchar uint vertex_indices")?;
+    writeln!(w, "end_header")?;
+    for v in vertices.chunks(6) {
+        writeln!(w, "{} {} {} {} {} {}", v[0], v[1], v[2], v[3], v[4], v[5])?;
+    }
+    for tri in indices.chunks(3) {
+        writeln!(w, "3 {} {} {}", tri[0], tri[1], tri[2])?;
+    }
+    Ok(())
+}
+
+/// Writes an indexed triangle mesh as a binary STL. STL has no shared vertices nor vertex
+/// normals, the face normals are computed from the winding.
+pub fn write_stl<W: Write>(w: &mut W, vertices: &[f32], indices: &[u32]) -> io::Result<()> {
+    let mut header = [0u8; 80];
+    let title = b"Planetary destruction simulator surface";
+    header[..title.len()].copy_from_slice(title);
+    w.write_all(&header)?;
+    write_u32(w, (indices.len() / 3) as u32)?;
+
+    let pos = |i: u32| {
+        let i = i as usize * 6;
+        [vertices[i], vertices[i + 1], vertices[i + 2]]
+    };
+    for tri in indices.chunks(3) {
+        let (a, b, c) = (pos(tri[0]), pos(tri[1]), pos(tri[2]));
+        let e1 = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+        let e2 = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+        let n = [
+            e1[1] * e2[2] - e1[2] * e2[1],
+            e1[2] * e2[0] - e1[0] * e2[2],
+            e1[0] * e2[1] - e1[1] * e2[0],
+        ];
+        let len = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+        let n = if len > 0f32 {
+            [n[0] / len, n[1] / len, n[2] / len]
+        } else {
+            n
+        };
+        for x in n.iter().chain(a.iter()).chain(b.iter()).chain(c.iter()) {
+            write_u32(w, x.to_bits())?;
+        }
+        // Attribute byte count, unused
+        w.write_all(&[0u8; 2])?;
+    }
+    Ok(())
+}
+
+fn write_u32<W: Write>(w: &mut W, x: u32) -> io::Result<()> {
+    w.write_all(&[x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8])
+}
+
+/// Writes the mass, flow and temperature volumes as legacy VTK structured points for eg.
+/// ParaView. The grid spans the [-1, 1] model space. The data is binary, which legacy VTK
+/// stores in big endian.
+pub fn write_vtk<W: Write>(w: &mut W, step: usize, dim: usize, fields: Fields) -> io::Result<()> {
+    writeln!(w, "# vtk DataFile Version 3.0")?;
+    writeln!(w, "Planetary destruction simulator, step {}", step)?;
+    writeln!(w, "BINARY")?;
+    writeln!(w, "DATASET STRUCTURED_POINTS")?;
+    writeln!(w, "DIMENSIONS {0} {0} {0}", dim)?;
+    writeln!(w, "ORIGIN -1 -1 -1")?;
+    let spacing = 2f32 / (dim - 1) as f32;
+    writeln!(w, "SPACING {0} {0} {0}", spacing)?;
+    // x changes fastest in both VTK and the simulation buffers
+    writeln!(w, "POINT_DATA {}", dim * dim * dim)?;
+
+    // Raw values where negative is inside the surface, see `normf`
+    writeln!(w, "SCALARS mass float 1")?;
+    writeln!(w, "LOOKUP_TABLE default")?;
+    write_f32s_be(w, fields.mass.iter())?;
+    writeln!(w)?;
+
+    writeln!(w, "VECTORS flow float")?;
+    write_f32s_be(w, fields.flow.chunks(FLOW_STRIDE).flat_map(|f| f[..3].iter()))?;
+    writeln!(w)?;
+
+    writeln!(w, "SCALARS temperature float 1")?;
+    writeln!(w, "LOOKUP_TABLE default")?;
+    write_f32s_be(w, fields.temp.iter())?;
+    writeln!(w)?;
+    Ok(())
+}
+
+fn write_f32s_be<'a, W, I>(w: &mut W, values: I) -> io::Result<()>
+where
+    W: Write,
+    I: Iterator<Item = &'a f32>,
+{
+    for x in values {
+        let x = x.to_bits();
+        w.write_all(&[(x >> 24) as u8, (x >> 16) as u8, (x >> 8) as u8, x as u8])?;
+    }
+    Ok(())
+}
+
+/// Writes the volumes into a VTK file at `path`, creating the parent directories if necessary.
+pub fn save_vtk<P: AsRef<Path>>(
+    path: P,
+    step: usize,
+    dim: usize,
+    fields: Fields,
+) -> io::Result<()> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut f = io::BufWriter::new(fs::File::create(path)?);
+    write_vtk(&mut f, step, dim, fields)?;
+    f.flush()
+}
+
+/// Writes the mesh in the given format into a file at `path`, creating the parent directories
+/// if necessary.
+pub fn save_mesh<P: AsRef<Path>>(
+    path: P,
+    format: MeshFormat,
+    vertices: &[f32],
+    indices: &[u32],
+) -> io::Result<()> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut f = io::BufWriter::new(fs::File::create(path)?);
+    match format {
+        MeshFormat::Obj => write_obj(&mut f, vertices, indices)?,
+        MeshFormat::Ply => write_ply(&mut f, vertices, indices)?,
+        MeshFormat::Stl => write_stl(&mut f, vertices, indices)?,
+    }
+    f.flush()
+}
+
+/// Writes the mesh into an OBJ file at `path`, creating the parent directories if necessary.
+pub fn save_obj<P: AsRef<Path>>(path: P, vertices: &[f32], indices: &[u32]) -> io::Result<()> {
+    save_mesh(path, MeshFormat::Obj, vertices, indices)
+}
+
+/// Path of the volumes exported on the given step, eg. "volume_000120.vtk". ParaView opens the
+/// numbered files in a directory as a time series.
+pub fn volume_path<P: AsRef<Path>>(dir: P, step: usize) -> PathBuf {
+    dir.as_ref().join(format!("volume_{:06}.vtk", step))
+}
+
+/// Path of the surface exported on the given step, eg. "surface_000120.obj". The numbering keeps
+/// the files in order for importing them as a sequence.
+pub fn surface_path<P: AsRef<Path>>(dir: P, step: usize, format: MeshFormat) -> PathBuf {
+    dir.as_ref()
+        .join(format!("surface_{:06}.{}", step, format.extension()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One triangle in the xy-plane facing +z, positions and normals interleaved
+    const VERTICES: [f32; 18] = [
+        0.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+        1.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+        0.0, 1.0, 0.0, 0.0, 0.0, 1.0,
+    ];
+    const INDICES: [u32; 3] = [0, 1, 2];
+
+    fn lines(bytes: Vec<u8>) -> Vec<String> {
+        String::from_utf8(bytes)
+            .unwrap()
+            .lines()
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    fn f32_le(bytes: &[u8]) -> f32 {
+        f32::from_bits(
+            bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16
+                | (bytes[3] as u32) << 24,
+        )
+    }
+
+    fn f32_be(bytes: &[u8]) -> f32 {
+        f32::from_bits(
+            (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8
+                | bytes[3] as u32,
+        )
+    }
+
+    #[test]
+    fn obj_has_one_based_faces() {
+        let mut out = vec![];
+        write_obj(&mut out, &VERTICES, &INDICES).unwrap();
+        let lines = lines(out);
+        assert!(lines[0].starts_with('#'));
+        assert_eq!(lines.iter().filter(|l| l.starts_with("v ")).count(), 3);
+        assert_eq!(lines.iter().filter(|l| l.starts_with("vn ")).count(), 3);
+        assert_eq!(lines[1], "v 0 0 0");
+        assert_eq!(lines[4], "vn 0 0 1");
+        assert_eq!(lines.last().unwrap(), "f 1//1 2//2 3//3");
+    }
+
+    #[test]
+    fn ply_header_counts_the_elements() {
+        let mut out = vec![];
+        write_ply(&mut out, &VERTICES, &INDICES).unwrap();
+        let lines = lines(out);
+        assert_eq!(lines[0], "ply");
+        assert_eq!(lines[1], "format ascii 1.0");
+        assert!(lines.contains(&"element vertex 3".to_string()));
+        assert!(lines.contains(&"element face 1".to_string()));
+        let body = lines.iter().position(|l| l == "end_header").unwrap() + 1;
+        assert_eq!(lines.len() - body, 4);
+        assert_eq!(lines[body + 1], "1 0 0 0 0 1");
+        assert_eq!(lines[body + 3], "3 0 1 2");
+    }
+
+    #[test]
+    fn stl_is_little_endian_with_face_normals() {
+        let mut out = vec![];
+        write_stl(&mut out, &VERTICES, &INDICES).unwrap();
+        // Header, triangle count and 50 bytes per triangle
+        assert_eq!(out.len(), 80 + 4 + 50);
+        assert!(out.starts_with(b"Planetary destruction simulator surface"));
+        assert_eq!(&out[80..84], &[1, 0, 0, 0]);
+        let floats: Vec<f32> = out[84..132].chunks(4).map(f32_le).collect();
+        assert_eq!(
+            floats,
+            vec![0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]
+        );
+        assert_eq!(&out[132..], &[0, 0]);
+    }
+
+    #[test]
+    fn vtk_is_big_endian_without_flow_padding() {
+        let dim = 2;
+        let mass: Vec<f32> = (0..8).map(|i| i as f32 - 3.5).collect();
+        // The padding of each flow is 99, which must not be written
+        let flow: Vec<f32> = (0..8 * FLOW_STRIDE)
+            .map(|i| if i % 4 == 3 { 99.0 } else { i as f32 })
+            .collect();
+        let temp: Vec<f32> = (0..8).map(|i| i as f32 * 0.25).collect();
+        let mut out = vec![];
+        let fields = Fields {
+            mass: &mass,
+            flow: &flow,
+            temp: &temp,
+        };
+        write_vtk(&mut out, 7, dim, fields).unwrap();
+
+        // The mass follows the first lookup table line
+        let table = b"LOOKUP_TABLE default\n";
+        let start = out.windows(table.len()).position(|w| w == table).unwrap() + table.len();
+        let header = String::from_utf8(out[..start].to_vec()).unwrap();
+        let header: Vec<&str> = header.lines().collect();
+        assert_eq!(header[0], "# vtk DataFile Version 3.0");
+        assert_eq!(header[1], "Planetary destruction simulator, step 7");
+        assert_eq!(header[2], "BINARY");
+        assert!(header.contains(&"DIMENSIONS 2 2 2"));
+        assert!(header.contains(&"SPACING 2 2 2"));
+        assert!(header.contains(&"POINT_DATA 8"));
+
+        let (mass_bytes, rest) = out[start..].split_at(8 * 4);
+        assert_eq!(&mass_bytes[..4], &[0xc0, 0x60, 0, 0]);
+        let read: Vec<f32> = mass_bytes.chunks(4).map(f32_be).collect();
+        assert_eq!(read, mass);
+
+        let vectors = b"\nVECTORS flow float\n";
+        assert!(rest.starts_with(vectors));
+        let (flow_bytes, rest) = rest[vectors.len()..].split_at(8 * 3 * 4);
+        let read: Vec<f32> = flow_bytes.chunks(4).map(f32_be).collect();
+        let expected: Vec<f32> = flow.chunks(FLOW_STRIDE)
+            .flat_map(|f| f[..3].to_vec())
+            .collect();
+        assert_eq!(read, expected);
+
+        let scalars = b"\nSCALARS temperature float 1\nLOOKUP_TABLE default\n";
+        assert!(rest.starts_with(scalars));
+        let (temp_bytes, rest) = rest[scalars.len()..].split_at(8 * 4);
+        let read: Vec<f32> = temp_bytes.chunks(4).map(f32_be).collect();
+        assert_eq!(read, temp);
+        assert_eq!(rest, b"\n");
+    }
+}
@@ -0,0 +1,162 @@
+use isosurface::source::Source;
+use prelude::*;
+
+/// Combines two signed distance fields into the volume covered by either one
+pub struct Union {
+    pub a: Box<Source>,
+    pub b: Box<Source>,
+}
+
+impl Source for Union {
+    fn sample(&self, x: f32, y: f32, z: f32) -> f32 {
+        self.a.sample(x, y, z).min(self.b.sample(x, y, z))
+    }
+}
+
+/// Cuts the volume of `b` out of `a`
+pub struct Subtraction {
+    pub a: Box<Source>,
+    pub b: Box<Source>,
+}
+
+impl Source for Subtraction {
+    fn sample(&self, x: f32, y: f32, z: f32) -> f32 {
+        self.a.sample(x, y, z).max(-self.b.sample(x, y, z))
+    }
+}
+
+/// The volume covered by both `a` and `b`
+pub struct Intersection {
+    pub a: Box<Source>,
+    pub b: Box<Source>,
+}
+
+impl Source for Intersection {
+    fn sample(&self, x: f32, y: f32, z: f32) -> f32 {
+        self.a.sample(x, y, z).max(self.b.sample(x, y, z))
+    }
+}
+
+/// A union that blends the surfaces together within the distance `k` of each other
+pub struct SmoothUnion {
+    pub a: Box<Source>,
+    pub b: Box<Source>,
+    pub k: f32,
+}
+
+impl Source for SmoothUnion {
+    fn sample(&self, x: f32, y: f32, z: f32) -> f32 {
+        let (a, b) = (self.a.sample(x, y, z), self.b.sample(x, y, z));
+        if self.k <= 0f32 {
+            return a.min(b);
+        }
+        // Polynomial smooth minimum
+        let h = (0.5f32 + 0.5f32 * (b - a) / self.k).max(0f32).min(1f32);
+        b + (a - b) * h - self.k * h * (1f32 - h)
+    }
+}
+
+/// Moves the inner field by `offset`
+pub struct Translate {
+    pub inner: Box<Source>,
+    pub offset: Vector3f,
+}
+
+impl Source for Translate {
+    fn sample(&self, x: f32, y: f32, z: f32) -> f32 {
+        let o = self.offset;
+        self.inner.sample(x - o.x, y - o.y, z - o.z)
+    }
+}
+
+/// Scales the inner field uniformly by `factor` around the origin. The factor must be positive.
+pub struct Scale {
+    pub inner: Box<Source>,
+    pub factor: f32,
+}
+
+impl Source for Scale {
+    fn sample(&self, x: f32, y: f32, z: f32) -> f32 {
+        let f = self.factor;
+        // Scale the distance back so that the field stays a distance field
+        self.inner.sample(x / f, y / f, z / f) * f
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::sphere::Sphere;
+
+    /// A sphere of radius 0.5 centered on the x-axis
+    fn sphere(x: f32) -> Box<Source> {
+        Box::new(Translate {
+            inner: Box::new(Sphere { radius: 0.5f32 }),
+            offset: Vector3f::new(x, 0f32, 0f32),
+        })
+    }
+
+    /// Samples `shape` on the x-axis and checks the expected values at (x, value) pairs
+    fn check(shape: &Source, expected: &[(f32, f32)]) {
+        for &(x, value) in expected {
+            let sampled = shape.sample(x, 0f32, 0f32);
+            assert!((sampled - value).abs() < 1e-6, "at {}: {} != {}", x, sampled, value);
+        }
+    }
+
+    // The spheres are centered at 0 and 0.6, they overlap between 0.1 and 0.5
+
+    #[test]
+    fn union_is_the_nearer_surface() {
+        let shape = Union {
+            a: sphere(0f32),
+            b: sphere(0.6f32),
+        };
+        check(&shape, &[(0f32, -0.5), (0.3, -0.2), (0.6, -0.5), (1.5, 0.4)]);
+    }
+
+    #[test]
+    fn intersection_is_the_farther_surface() {
+        let shape = Intersection {
+            a: sphere(0f32),
+            b: sphere(0.6f32),
+        };
+        check(&shape, &[(0f32, 0.1), (0.3, -0.2), (0.6, 0.1), (-1.0, 1.1)]);
+    }
+
+    #[test]
+    fn subtraction_cuts_b_out_of_a() {
+        let shape = Subtraction {
+            a: sphere(0f32),
+            b: sphere(0.6f32),
+        };
+        check(&shape, &[(0f32, -0.1), (-0.2, -0.3), (0.3, 0.2), (0.6, 0.5)]);
+    }
+
+    #[test]
+    fn smooth_union_blends_within_k() {
+        let shape = SmoothUnion {
+            a: sphere(0f32),
+            b: sphere(0.6f32),
+            k: 0.1f32,
+        };
+        // Halfway between the spheres both are 0.2 deep and the blend adds k / 4
+        check(&shape, &[(0f32, -0.5), (0.3, -0.225), (0.6, -0.5), (-1.0, 0.5)]);
+
+        let sharp = SmoothUnion {
+            a: sphere(0f32),
+            b: sphere(0.6f32),
+            k: 0f32,
+        };
+        check(&sharp, &[(0.3, -0.2)]);
+    }
+
+    #[test]
+    fn scale_keeps_distances() {
+        let shape = Scale {
+            inner: sphere(0f32),
+            factor: 2f32,
+        };
+        check(&shape, &[(0f32, -1.0), (1.0, 0.0), (1.5, 0.5)]);
+    }
+}
@@ -0,0 +1,100 @@
+use prelude::*;
+
+/// Walks the cells of a `dim`³ grid that a ray passes through, in order from the ray origin
+/// (Amanatides & Woo). Positions are in cell units where the center of the cell (x, y, z) is at
+/// (x, y, z). Yields the cells as (x, y, z).
+pub struct GridRay {
+    cell: [isize; 3],
+    step: [isize; 3],
+    // Ray parameter at which the next cell boundary is crossed, per axis
+    t_max: [f32; 3],
+    // Ray parameter between two cell boundaries, per axis
+    t_delta: [f32; 3],
+    dim: isize,
+}
+
+impl GridRay {
+    /// Returns `None` if the ray does not hit the grid.
+    pub fn new(origin: Vector3f, dir: Vector3f, dim: usize) -> Option<GridRay> {
+        // Shift by half a cell so that the cell i covers [i, i + 1[
+        let origin = origin + Vector3f::new(0.5f32, 0.5f32, 0.5f32);
+        let size = dim as f32;
+
+        // Clip the ray against the grid bounds
+        let mut t_enter = 0f32;
+        let mut t_exit = ::std::f32::INFINITY;
+        for axis in 0..3 {
+            if dir[axis] == 0f32 {
+                if origin[axis] < 0f32 || origin[axis] >= size {
+                    return None;
+                }
+                continue;
+            }
+            let t0 = (0f32 - origin[axis]) / dir[axis];
+            let t1 = (size - origin[axis]) / dir[axis];
+            t_enter = t_enter.max(t0.min(t1));
+            t_exit = t_exit.min(t0.max(t1));
+        }
+        if t_exit < t_enter {
+            return None;
+        }
+
+        let entry = origin + dir * t_enter;
+        let mut cell = [0isize; 3];
+        let mut step = [0isize; 3];
+        let mut t_max = [::std::f32::INFINITY; 3];
+        let mut t_delta = [::std::f32::INFINITY; 3];
+        for axis in 0..3 {
+            cell[axis] = (entry[axis].floor() as isize).max(0).min(dim as isize - 1);
+            if dir[axis] > 0f32 {
+                step[axis] = 1;
+                t_max[axis] = t_enter + ((cell[axis] + 1) as f32 - entry[axis]) / dir[axis];
+                t_delta[axis] = 1f32 / dir[axis];
+            } else if dir[axis] < 0f32 {
+                step[axis] = -1;
+                t_max[axis] = t_enter + (cell[axis] as f32 - entry[axis]) / dir[axis];
+                t_delta[axis] = -1f32 / dir[axis];
+            }
+        }
+
+        Some(GridRay {
+            cell,
+            step,
+            t_max,
+            t_delta,
+            dim: dim as isize,
+        })
+    }
+}
+
+impl Iterator for GridRay {
+    type Item = (usize, usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cell.iter().any(|&c| c < 0 || c >= self.dim) {
+            return None;
+        }
+        let current = (
+            self.cell[0] as usize,
+            self.cell[1] as usize,
+            self.cell[2] as usize,
+        );
+
+        // Step into the neighbor through the closest cell boundary
+        let mut axis = 0;
+        for a in 1..3 {
+            if self.t_max[a] < self.t_max[axis] {
+                axis = a;
+            }
+        }
+        if self.step[axis] == 0 {
+            // Zero direction, nothing more to walk
+            self.cell[0] = -1;
+        } else {
+            self.cell[axis] += self.step[axis];
+            self.t_max[axis] += self.t_delta[axis];
+        }
+
+        Some(current)
+    }
+}
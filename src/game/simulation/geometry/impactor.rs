@@ -0,0 +1,62 @@
+use prelude::*;
+use cgmath::InnerSpace;
+
+/// An asteroid that travels ballistically towards the planet. Positions and velocities are in
+/// cell units.
+#[derive(Clone, Debug)]
+pub struct Impactor {
+    pub pos: Vector3f,
+    pub vel: Vector3f,
+    /// Mass in units of one full cell
+    pub mass: f32,
+    /// Radius in cells
+    pub radius: f32,
+}
+
+/// What happened to an impactor during a step.
+pub enum ImpactorState {
+    Flying,
+    /// Hit the surface at the given cell (x, y, z)
+    Hit((usize, usize, usize)),
+    /// Left the vicinity of the grid and can be dropped
+    Lost,
+}
+
+impl Impactor {
+    /// Advances the impactor by `dt`. `accel` returns the gravity acceleration at a position and
+    /// `is_mass` tells whether the cell (x, y, z) is inside the surface.
+    pub fn update<A, F>(&mut self, dt: f32, dim: usize, accel: A, is_mass: F) -> ImpactorState
+    where
+        A: Fn(Vector3f) -> Vector3f,
+        F: Fn(usize, usize, usize) -> bool,
+    {
+        // Sub-step so that the impactor moves less than half a cell at a time and can't tunnel
+        let n = ((self.vel.magnitude() * dt) / 0.5f32).ceil().max(1f32) as usize;
+        let sub_dt = dt / n as f32;
+        for _ in 0..n {
+            self.vel += accel(self.pos) * sub_dt;
+            self.pos += self.vel * sub_dt;
+
+            let cell = (
+                self.pos.x.round(),
+                self.pos.y.round(),
+                self.pos.z.round(),
+            );
+            let inside = |c: f32| c >= 0f32 && c < dim as f32;
+            if inside(cell.0) && inside(cell.1) && inside(cell.2) {
+                let cell = (cell.0 as usize, cell.1 as usize, cell.2 as usize);
+                if is_mass(cell.0, cell.1, cell.2) {
+                    return ImpactorState::Hit(cell);
+                }
+            }
+        }
+
+        let center = (dim - 1) as f32 / 2f32;
+        let from_center = self.pos - Vector3f::new(center, center, center);
+        if from_center.magnitude() > 2f32 * dim as f32 {
+            ImpactorState::Lost
+        } else {
+            ImpactorState::Flying
+        }
+    }
+}
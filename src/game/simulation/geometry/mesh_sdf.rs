@@ -0,0 +1,376 @@
+use isosurface::source::Source;
+use prelude::*;
+use cgmath::InnerSpace;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use util;
+
+/// Half of the side length of the box in the [-1, 1] model space that meshes are fitted into,
+/// leaves room around the body for it to deform
+const FIT_EXTENT: f32 = 0.75f32;
+
+/// Average number of triangles per cell of the grid that the triangles are bucketed into
+const TRIANGLES_PER_CELL: f32 = 1f32;
+/// Upper bound for the side length of the grid in cells
+const MAX_GRID_RES: usize = 64;
+
+/// The signed distance field of a closed triangle mesh. The sign is negative inside the mesh.
+pub struct MeshSdf {
+    triangles: Vec<[Vector3f; 3]>,
+    grid: TriangleGrid,
+}
+
+/// The triangles bucketed into a uniform grid of cubic cells over their bounding box, so that a
+/// query only visits the triangles close to it. A triangle is listed in every cell that its
+/// bounding box overlaps.
+struct TriangleGrid {
+    min: Vector3f,
+    cell_size: f32,
+    res: usize,
+    cells: Vec<Vec<u32>>,
+}
+
+impl MeshSdf {
+    /// Loads a Wavefront OBJ, centered and scaled to fit the model space.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<MeshSdf> {
+        let mut data = vec![];
+        fs::File::open(path)?.read_to_end(&mut data)?;
+        let triangles = util::load_wavefront_triangles(&data)?;
+        if triangles.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the mesh has no triangles",
+            ));
+        }
+        Ok(MeshSdf::fitted(
+            triangles
+                .iter()
+                .map(|t| {
+                    [
+                        Vector3f::from(t[0]),
+                        Vector3f::from(t[1]),
+                        Vector3f::from(t[2]),
+                    ]
+                })
+                .collect(),
+        ))
+    }
+
+    /// Centers the bounding box of the triangles at the origin and scales its longest side to
+    /// `2 * FIT_EXTENT`.
+    pub fn fitted(mut triangles: Vec<[Vector3f; 3]>) -> MeshSdf {
+        let (min, max) = bounds(triangles.iter().flat_map(|t| t.iter()));
+        let center = (min + max) * 0.5f32;
+        let size = max - min;
+        let longest = size.x.max(size.y).max(size.z);
+        let scale = if longest > 0f32 {
+            2f32 * FIT_EXTENT / longest
+        } else {
+            1f32
+        };
+        for v in triangles.iter_mut().flat_map(|t| t.iter_mut()) {
+            *v = (*v - center) * scale;
+        }
+        let grid = TriangleGrid::new(&triangles);
+        MeshSdf { triangles, grid }
+    }
+
+    /// Squared distance from `p` to the closest triangle. Visits the cells of the grid in rings
+    /// around the cell of `p` until the next ring can't hold anything closer.
+    fn closest_dist2(&self, p: Vector3f) -> f32 {
+        let grid = &self.grid;
+        let center = grid.clamped_cell(p);
+        let mut best = ::std::f32::MAX;
+        for ring in 0..grid.res as isize {
+            // Cells on this ring are at least `ring - 1` cells away from `p`, and the projection
+            // of a point outside of the grid onto it is in the center cell
+            let reach = (ring - 1).max(0) as f32 * grid.cell_size;
+            if best <= reach * reach {
+                break;
+            }
+            grid.for_each_on_ring(center, ring, |cell| {
+                for &i in cell {
+                    let t = &self.triangles[i as usize];
+                    best = best.min((closest_point_on_triangle(p, t) - p).magnitude2());
+                }
+            });
+        }
+        best
+    }
+
+    /// Whether the point is inside the mesh: a ray along +x crosses the surface an odd number
+    /// of times. Only the cells on the row of the ray are visited, and each triangle is tested
+    /// once even if it spans several of them.
+    fn inside(&self, p: Vector3f) -> bool {
+        let grid = &self.grid;
+        let c = grid.cell(p);
+        let (x, y, z) = (c[0], c[1], c[2]);
+        let res = grid.res as isize;
+        let in_grid = |c: isize| c >= 0 && c < res;
+        if !in_grid(y) || !in_grid(z) || x >= res {
+            return false;
+        }
+        let mut candidates: Vec<u32> = (x.max(0)..res)
+            .flat_map(|x| grid.cells[grid.index(x, y, z)].iter().cloned())
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        let crossings = candidates
+            .iter()
+            .filter(|&&i| ray_hits_triangle(p, Vector3f::unit_x(), &self.triangles[i as usize]))
+            .count();
+        crossings % 2 == 1
+    }
+}
+
+impl Source for MeshSdf {
+    fn sample(&self, x: f32, y: f32, z: f32) -> f32 {
+        let p = Vector3f::new(x, y, z);
+        let dist = self.closest_dist2(p).sqrt();
+        if self.inside(p) {
+            -dist
+        } else {
+            dist
+        }
+    }
+}
+
+impl TriangleGrid {
+    fn new(triangles: &[[Vector3f; 3]]) -> TriangleGrid {
+        let (min, max) = bounds(triangles.iter().flat_map(|t| t.iter()));
+        let size = max - min;
+        let res = ((triangles.len() as f32 / TRIANGLES_PER_CELL).cbrt().ceil() as usize)
+            .max(1)
+            .min(MAX_GRID_RES);
+        // Cubic cells, padded so that the vertices on the max side fall inside the grid
+        let longest = size.x.max(size.y).max(size.z).max(1e-6f32);
+        let cell_size = longest * 1.0001f32 / res as f32;
+
+        let mut grid = TriangleGrid {
+            min,
+            cell_size,
+            res,
+            cells: vec![vec![]; res * res * res],
+        };
+        for (i, t) in triangles.iter().enumerate() {
+            let (t_min, t_max) = bounds(t.iter());
+            let lo = grid.clamped_cell(t_min);
+            let hi = grid.clamped_cell(t_max);
+            for z in lo[2]..hi[2] + 1 {
+                for y in lo[1]..hi[1] + 1 {
+                    for x in lo[0]..hi[0] + 1 {
+                        let idx = grid.index(x, y, z);
+                        grid.cells[idx].push(i as u32);
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    /// The cell that contains `p`, possibly outside of the grid
+    fn cell(&self, p: Vector3f) -> [isize; 3] {
+        let c = |i: usize| ((p[i] - self.min[i]) / self.cell_size).floor() as isize;
+        [c(0), c(1), c(2)]
+    }
+
+    /// The cell of the grid that is closest to `p`
+    fn clamped_cell(&self, p: Vector3f) -> [isize; 3] {
+        let last = self.res as isize - 1;
+        let c = self.cell(p);
+        [
+            c[0].max(0).min(last),
+            c[1].max(0).min(last),
+            c[2].max(0).min(last),
+        ]
+    }
+
+    fn index(&self, x: isize, y: isize, z: isize) -> usize {
+        (z as usize * self.res + y as usize) * self.res + x as usize
+    }
+
+    /// Calls `f` with the cells of the grid that are exactly `ring` cells away from `center`
+    /// along at least one axis.
+    fn for_each_on_ring<F>(&self, center: [isize; 3], ring: isize, mut f: F)
+    where
+        F: FnMut(&[u32]),
+    {
+        let res = self.res as isize;
+        let in_grid = |c: isize| c >= 0 && c < res;
+        for dz in -ring..ring + 1 {
+            for dy in -ring..ring + 1 {
+                // Inside the shell only the two cells at the ends of the row are on the ring
+                let on_shell = dz.abs() == ring || dy.abs() == ring;
+                let step = if on_shell || ring == 0 { 1 } else { 2 * ring };
+                let (z, y) = (center[2] + dz, center[1] + dy);
+                if !in_grid(z) || !in_grid(y) {
+                    continue;
+                }
+                let mut dx = -ring;
+                while dx <= ring {
+                    let x = center[0] + dx;
+                    if in_grid(x) {
+                        f(&self.cells[self.index(x, y, z)]);
+                    }
+                    dx += step;
+                }
+            }
+        }
+    }
+}
+
+/// The bounding box of the points
+fn bounds<'a, I>(points: I) -> (Vector3f, Vector3f)
+where
+    I: Iterator<Item = &'a Vector3f>,
+{
+    let mut min = Vector3f::new(::std::f32::MAX, ::std::f32::MAX, ::std::f32::MAX);
+    let mut max = -min;
+    for v in points {
+        for i in 0..3 {
+            min[i] = min[i].min(v[i]);
+            max[i] = max[i].max(v[i]);
+        }
+    }
+    (min, max)
+}
+
+/// Moeller-Trumbore intersection of the ray starting from `origin` towards `dir`.
+fn ray_hits_triangle(origin: Vector3f, dir: Vector3f, t: &[Vector3f; 3]) -> bool {
+    const EPSILON: f32 = 1e-7;
+    let e1 = t[1] - t[0];
+    let e2 = t[2] - t[0];
+    let h = dir.cross(e2);
+    let a = e1.dot(h);
+    if a.abs() < EPSILON {
+        // Parallel to the triangle
+        return false;
+    }
+    let f = 1f32 / a;
+    let s = origin - t[0];
+    let u = f * s.dot(h);
+    if u < 0f32 || u > 1f32 {
+        return false;
+    }
+    let q = s.cross(e1);
+    let v = f * dir.dot(q);
+    if v < 0f32 || u + v > 1f32 {
+        return false;
+    }
+    f * e2.dot(q) > 0f32
+}
+
+/// The point on the triangle that is closest to `p`, from Ericson's Real-Time Collision
+/// Detection.
+fn closest_point_on_triangle(p: Vector3f, t: &[Vector3f; 3]) -> Vector3f {
+    let (a, b, c) = (t[0], t[1], t[2]);
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0f32 && d2 <= 0f32 {
+        return a;
+    }
+
+    let bp = p - b;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0f32 && d4 <= d3 {
+        return b;
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0f32 && d1 >= 0f32 && d3 <= 0f32 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+
+    let cp = p - c;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0f32 && d5 <= d6 {
+        return c;
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0f32 && d2 >= 0f32 && d6 <= 0f32 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0f32 && (d4 - d3) >= 0f32 && (d5 - d6) >= 0f32 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+
+    // Inside the face
+    let denom = 1f32 / (va + vb + vc);
+    a + ab * (vb * denom) + ac * (vc * denom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    /// A closed latitude-longitude sphere of unit radius
+    fn sphere_triangles(rings: usize, segments: usize) -> Vec<[Vector3f; 3]> {
+        let vertex = |ring: usize, segment: usize| {
+            let theta = PI * ring as f32 / rings as f32;
+            let phi = 2f32 * PI * segment as f32 / segments as f32;
+            Vector3f::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos())
+        };
+        let mut triangles = vec![];
+        for ring in 0..rings {
+            for segment in 0..segments {
+                let a = vertex(ring, segment);
+                let b = vertex(ring + 1, segment);
+                let c = vertex(ring + 1, segment + 1);
+                let d = vertex(ring, segment + 1);
+                if ring > 0 {
+                    triangles.push([a, b, d]);
+                }
+                if ring < rings - 1 {
+                    triangles.push([b, c, d]);
+                }
+            }
+        }
+        triangles
+    }
+
+    /// Points that cover the [-1, 1] model space evenly
+    fn points() -> Vec<Vector3f> {
+        (0..1000)
+            .map(|i| {
+                let f = |k: f32| ((i as f32 + 0.5f32) * k).fract() * 2f32 - 1f32;
+                Vector3f::new(f(0.8191725f32), f(0.6710436f32), f(0.5497005f32))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn grid_finds_the_closest_triangle() {
+        let sdf = MeshSdf::fitted(sphere_triangles(16, 32));
+        for p in points() {
+            let brute_force = sdf.triangles
+                .iter()
+                .map(|t| (closest_point_on_triangle(p, t) - p).magnitude2())
+                .fold(::std::f32::MAX, f32::min);
+            assert_eq!(sdf.closest_dist2(p), brute_force, "{:?}", p);
+        }
+    }
+
+    #[test]
+    fn inside_matches_the_sphere() {
+        // The sphere is fitted to the radius FIT_EXTENT, the facets are within 0.02 of it
+        let sdf = MeshSdf::fitted(sphere_triangles(16, 32));
+        for p in points() {
+            let dist = p.magnitude() - FIT_EXTENT;
+            if dist.abs() > 0.02f32 {
+                assert_eq!(sdf.inside(p), dist < 0f32, "{:?}", p);
+            }
+        }
+    }
+}
@@ -1,22 +1,48 @@
 #![allow(unused_imports)]
 #![allow(dead_code)]
+mod csg;
+mod grid_ray;
+mod impactor;
+mod mesh_sdf;
+mod noise;
 mod torus;
 mod scalar_field;
+mod scene;
 mod sphere;
+mod terrain;
 
 use glium::*;
+use glium::backend::Facade;
 use glium::index::*;
 use isosurface::source::*;
 use isosurface::marching_cubes::*;
 use util;
 use util::*;
 use prelude::*;
+use cgmath::prelude::*;
+use self::grid_ray::*;
+use self::impactor::*;
+use self::mesh_sdf::*;
 use self::torus::*;
 use self::scalar_field::*;
+use self::scene::*;
 use self::sphere::*;
-use super::ocl_liquid_sim as cl;
-use ocl;
+use self::terrain::*;
+use super::backend::*;
+use super::export;
+use super::export::MeshFormat;
+use super::gravity;
+use super::snapshot::Snapshot;
+use game::settings::Settings;
 use ndarray::prelude::*;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Radius of the laser beam in cells
+const LASER_RADIUS: f32 = 1.5f32;
+/// How deep the laser reaches into the surface in cells
+const LASER_DEPTH: f32 = 2f32;
 
 pub struct GeometryGen {
     marching_cubes: MarchingCubes,
@@ -26,115 +52,346 @@ pub struct GeometryGen {
     temperatures: [Array3<f32>; 2],
     frame_count: usize,
     dim: usize,
-    kernel: ocl::Kernel,
-    mass_dbl_buf: [ocl::Buffer<f32>; 2],
-    flow_dbl_buf: [ocl::Buffer<f32>; 2],
-    temperature_dbl_buf: [ocl::Buffer<f32>; 2],
+    backend: Box<SimulationBackend>,
     laser: bool,
+    // Laser origin and normalized direction in the [-1, 1] model space
+    laser_ray: (Vector3f, Vector3f),
     laser_strength: f32,
+    impactors: Vec<Impactor>,
+    impactor_mass: f32,
+    // In the [-1, 1] model space
+    impactor_radius: f32,
+    impactor_speed: f32,
+    gravity_constant: f32,
+    boundary: Boundary,
+    // The mass that the laser and the impactors are traced against and the step it's from.
+    // Refreshed once per drawn frame, so that the fixed steps don't wait for the device.
+    traced_mass: Vec<f32>,
+    traced_step: Option<usize>,
+    // How much of the host-side buffers that are read on this step match the backend's copies
+    host_sync: HostSync,
+    readback: ReadbackStats,
 }
 
-impl GeometryGen {
-    /// dim: scalar field side length
-    pub fn new(dim: usize, fixed_dt: f32, laser_strength: f32) -> GeometryGen {
-        // p = 0.145f32 is a nice default for max-size
-        const PLANET_RADIUS: f32 = 0.1f32;
-        const SURFACE_UNEVENNESS: f32 = PLANET_RADIUS * 0.1f32;
-        let model = ScalarField::new(dim, PLANET_RADIUS, SURFACE_UNEVENNESS);
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HostSync {
+    Stale,
+    Mass,
+    All,
+}
+
+/// Amount of data and time spent copying the simulation buffers from the device to the host
+#[derive(Clone, Debug, Default)]
+pub struct ReadbackStats {
+    pub count: usize,
+    pub bytes: usize,
+    pub time: Duration,
+}
+
+impl ReadbackStats {
+    pub fn millis(&self) -> f64 {
+        self.time.as_secs() as f64 * 1e3 + self.time.subsec_nanos() as f64 * 1e-6
+    }
+
+    fn add(&mut self, bytes: usize, start: Instant) {
+        // Backends that work on the host-side buffers don't copy anything
+        if bytes == 0 {
+            return;
+        }
+        self.count += 1;
+        self.bytes += bytes;
+        self.time += start.elapsed();
+    }
+}
 
-        let cell_dist = 1f32 / (dim - 2) as f32;
-        let mut source_0 = CentralDifference::new_with_epsilon(model.clone(), cell_dist);
-        let mut source_1 = CentralDifference::new_with_epsilon(model, cell_dist);
+/// Summary of the simulation state after a step.
+#[derive(Clone, Debug)]
+pub struct FieldStats {
+    pub step: usize,
+    pub filled_cells: usize,
+    pub total_mass: f32,
+    pub mean_temperature: f32,
+    pub max_temperature: f32,
+    /// Mass deleted by open boundaries since the start
+    pub ejected_mass: f32,
+}
+
+impl FieldStats {
+    pub const CSV_HEADER: &'static str =
+        "step,filled_cells,total_mass,mean_temperature,max_temperature,ejected_mass";
+
+    pub fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{},{}",
+            self.step,
+            self.filled_cells,
+            self.total_mass,
+            self.mean_temperature,
+            self.max_temperature,
+            self.ejected_mass
+        )
+    }
+}
 
+impl GeometryGen {
+    /// Builds the initial planet and the simulation backend selected in `cfg`.
+    pub fn new(cfg: &Settings, fixed_dt: f32) -> GeometryGen {
+        let dim = cfg.scalar_field_dim;
+        let model = initial_field(cfg, dim);
         // Reserve space for float3's instead of floats
-        let flow_0 = Array::default((3 * dim, 3 * dim, 3 * dim));
-        let flow_1 = Array::default((3 * dim, 3 * dim, 3 * dim));
+        let flow = Array::default((dim, dim, FLOW_STRIDE * dim));
+        let temperature = Array::from_elem((dim, dim, dim), 0f32);
+        GeometryGen::from_fields(cfg, fixed_dt, model, flow, temperature, 0)
+    }
+
+    /// Builds the simulation with the settings of the snapshot and continues from its state.
+    pub fn from_snapshot(snapshot: &Snapshot, fixed_dt: f32) -> GeometryGen {
+        let dim = snapshot.dim;
+        let model = ScalarField::from_cells(dim, snapshot.mass.clone());
+        let flow = Array::from_shape_vec((dim, dim, FLOW_STRIDE * dim), snapshot.flow.clone())
+            .expect("the flow of the snapshot doesn't match its dim");
+        let temperature = Array::from_shape_vec((dim, dim, dim), snapshot.temp.clone())
+            .expect("the temperature of the snapshot doesn't match its dim");
+        GeometryGen::from_fields(
+            &snapshot.settings,
+            fixed_dt,
+            model,
+            flow,
+            temperature,
+            snapshot.frame_count,
+        )
+    }
+
+    /// Fills both buffers with the given fields, so that the mesh is right before the first
+    /// step, and creates the backend on them.
+    fn from_fields(
+        cfg: &Settings,
+        fixed_dt: f32,
+        model: ScalarField,
+        flow: Array3<f32>,
+        temperature: Array3<f32>,
+        frame_count: usize,
+    ) -> GeometryGen {
+        let dim = model.dim();
+        let cell_dist = model.cell_dist();
+        let source_0 = CentralDifference::new_with_epsilon(model.clone(), cell_dist);
+        let source_1 = CentralDifference::new_with_epsilon(model, cell_dist);
 
-        let temperature_0 = Array::from_elem((dim, dim, dim), 0f32);
-        let temperature_1 = Array::from_elem((dim, dim, dim), 0f32);
+        let flow_0 = flow.clone();
+        let flow_1 = flow;
+
+        let temperature_0 = temperature.clone();
+        let temperature_1 = temperature;
 
         let marching_cubes = MarchingCubes::new(dim);
 
-        // Initialize OpenCL
-        debug!("initializing OpenCL");
-        // TODO: refactor these into an OpenCL-struct
-        let (kernel, mass_dbl_buf, flow_dbl_buf, temperature_dbl_buf) = cl::bind(
-            "src/game/simulation/cl/liquid_sim.cl",
-            "simulate_liquid",
-            [
-                source_0.inner_mut().into_slice(),
-                source_1.inner_mut().into_slice(),
-            ],
-            [
-                flow_0.view().into_slice().unwrap(),
-                flow_1.view().into_slice().unwrap(),
-            ],
+        let params = SimulationParams {
+            dim,
+            fixed_dt,
+            cell_dist: physics_cell_dist(dim),
+            radiate: cfg.temperature_radiate,
+            diffuse: cfg.temperature_diffuse,
+            melt_temp: cfg.melting_temperature,
+            gravity_constant: cfg.gravity_constant,
+            boundary: Boundary::from_name(&cfg.boundary),
+            platform: cfg.opencl_platform.clone(),
+            device: cfg.opencl_device.clone(),
+            marching_cubes: cfg.device_marching_cubes,
+        };
+        let backend = create_backend(
+            BackendKind::from_name(&cfg.simulation_backend),
+            &params,
             [
-                temperature_0.view().into_slice().unwrap(),
-                temperature_1.view().into_slice().unwrap(),
+                Fields {
+                    mass: source_0.inner().into_slice(),
+                    flow: flow_0.as_slice().unwrap(),
+                    temp: temperature_0.as_slice().unwrap(),
+                },
+                Fields {
+                    mass: source_1.inner().into_slice(),
+                    flow: flow_1.as_slice().unwrap(),
+                    temp: temperature_1.as_slice().unwrap(),
+                },
             ],
-            ocl::SpatialDims::Three(dim, dim, dim),
-            fixed_dt,
-            cell_dist,
         );
-        debug!("OpenCL init success");
+        info!("using the {} simulation backend", backend.name());
 
         GeometryGen {
             marching_cubes,
             sources: [source_0, source_1],
             flows: [flow_0, flow_1],
             temperatures: [temperature_0, temperature_1],
-            frame_count: 0,
+            frame_count,
             dim: dim,
-            kernel,
-            mass_dbl_buf,
-            flow_dbl_buf,
-            temperature_dbl_buf,
+            backend,
             laser: false,
-            laser_strength,
+            // Shoot from the initial camera position until aimed elsewhere
+            laser_ray: (Vector3f::new(0f32, 0f32, -1.5f32), Vector3f::unit_z()),
+            laser_strength: cfg.laser_strength,
+            impactors: vec![],
+            impactor_mass: cfg.impactor_mass,
+            impactor_radius: cfg.impactor_radius,
+            impactor_speed: cfg.impactor_speed,
+            gravity_constant: cfg.gravity_constant,
+            boundary: params.boundary,
+            traced_mass: vec![],
+            traced_step: None,
+            host_sync: HostSync::All,
+            readback: ReadbackStats::default(),
         }
     }
 
-    pub fn fixed_update(&mut self, _: f32) {
-        if self.laser {
-            let src = &mut self.temperatures[self.frame_count % 2];
-            let len = src.len_of(Axis(0));
-            let pos = len / 2;
-            let line = src.slice_mut(s![pos..pos + 1, pos..pos + 1, ..pos;-1]);
+    pub fn fixed_update(&mut self, dt: f32) {
+        self.update_impactors(dt);
 
-            for elem in line {
-                *elem = 1f32;
+        if self.laser {
+            self.update_traced_mass();
+            if let Some(beam) = self.laser_beam() {
+                let temp = self.temperatures[self.frame_count % 2]
+                    .as_slice_mut()
+                    .unwrap();
+                self.backend.inject_heat(temp, &beam, self.frame_count);
             }
         }
 
-        // Run the OpenCL kernel for the liquid simulation
-        cl::call(
-            &self.kernel,
-            &self.mass_dbl_buf,
-            &self.flow_dbl_buf,
-            &self.temperature_dbl_buf,
-            self.frame_count,
+        // Run the liquid simulation
+        let frame_count = self.frame_count;
+        let (src_mass, dst_mass) = split_dbl_buf(&mut self.sources, frame_count);
+        let (src_flow, dst_flow) = split_dbl_buf(&mut self.flows, frame_count);
+        let (src_temp, dst_temp) = split_dbl_buf(&mut self.temperatures, frame_count);
+        self.backend.simulate(
+            Fields {
+                mass: src_mass.inner().into_slice(),
+                flow: src_flow.as_slice().unwrap(),
+                temp: src_temp.as_slice().unwrap(),
+            },
+            FieldsMut {
+                mass: dst_mass.inner_mut().elems_mut().as_slice_mut().unwrap(),
+                flow: dst_flow.as_slice_mut().unwrap(),
+                temp: dst_temp.as_slice_mut().unwrap(),
+            },
+            frame_count,
         );
         self.frame_count += 1;
+        self.host_sync = HostSync::Stale;
     }
 
-    pub fn update_vbo(
+    /// Copies the mass that is read on the next step from the backend, unless it's up to date.
+    fn sync_mass(&mut self) {
+        if self.host_sync != HostSync::Stale {
+            return;
+        }
+        let start = Instant::now();
+        let bytes = self.backend.download_mass(
+            self.sources[self.frame_count % 2]
+                .inner_mut()
+                .elems_mut()
+                .as_slice_mut()
+                .unwrap(),
+            self.frame_count,
+        );
+        self.readback.add(bytes, start);
+        self.host_sync = HostSync::Mass;
+    }
+
+    /// Copies the mass that is read on the next step into the traced mass.
+    fn refresh_traced_mass(&mut self) {
+        self.sync_mass();
+        self.traced_mass.clear();
+        self.traced_mass
+            .extend_from_slice(self.sources[self.frame_count % 2].inner().into_slice());
+        self.traced_step = Some(self.frame_count);
+    }
+
+    /// Called before the laser and the impactors are traced on a fixed step. Only copies the
+    /// mass from the backend if there's no traced mass yet, otherwise the mass of the last drawn
+    /// frame is used. The host-side buffers are used when they're up to date anyway, eg. after
+    /// the statistics of a headless step.
+    fn update_traced_mass(&mut self) {
+        let host_is_newer =
+            self.host_sync != HostSync::Stale && self.traced_step != Some(self.frame_count);
+        if self.traced_step.is_none() || host_is_newer {
+            self.refresh_traced_mass();
+        }
+    }
+
+    /// Copies all of the fields that are read on the next step from the backend, unless they're
+    /// up to date.
+    fn sync_all(&mut self) {
+        if self.host_sync == HostSync::All {
+            return;
+        }
+        let read = self.frame_count % 2;
+        let start = Instant::now();
+        let bytes = self.backend.download(
+            FieldsMut {
+                mass: self.sources[read]
+                    .inner_mut()
+                    .elems_mut()
+                    .as_slice_mut()
+                    .unwrap(),
+                flow: self.flows[read].as_slice_mut().unwrap(),
+                temp: self.temperatures[read].as_slice_mut().unwrap(),
+            },
+            self.frame_count,
+        );
+        self.readback.add(bytes, start);
+        self.host_sync = HostSync::All;
+    }
+
+    /// Copies the fields that are read on the next step from the backend: all of them, or only
+    /// the mass like for a drawn frame. For measuring the copies, see `--readback-bench`.
+    pub fn read_back(&mut self, all: bool) {
+        if all {
+            self.sync_all();
+        } else {
+            self.sync_mass();
+        }
+    }
+
+    /// The copies from the device to the host since the start
+    pub fn readback_stats(&self) -> &ReadbackStats {
+        &self.readback
+    }
+
+    pub fn update_vbo<F: Facade>(
         &mut self,
         vbo: &mut VertexBuffer<VertexPN>,
         ibo: &mut IndexBuffer<u32>,
-        display: &Display,
+        display: &F,
     ) {
+        let (vertices, indices) = self.extract_mesh();
+
+        // The laser and the impactors are traced against the mass of the last drawn frame
+        if self.laser || !self.impactors.is_empty() {
+            self.refresh_traced_mass();
+        } else {
+            self.traced_step = None;
+        }
+
+        *vbo = VertexBuffer::dynamic(display, util::reinterpret_cast_slice(&vertices))
+            .expect("failed to create vertex buffer");
+        *ibo = IndexBuffer::dynamic(display, PrimitiveType::TrianglesList, &indices)
+            .expect("failed to create index buffer");
+    }
+
+    /// Runs marching cubes on the latest buffer, on the device if the backend supports it.
+    /// Returns interleaved positions and normals (6 floats per vertex) in the [-1, 1] model
+    /// space and the triangle indices.
+    pub fn extract_mesh(&mut self) -> (Vec<f32>, Vec<u32>) {
         // Note: the n:o vertices/indices changes over time.
         let mut vertices = vec![];
         let mut indices = vec![];
-        // Get the latest buffer
-        let nbuf = &self.sources[(self.frame_count + 1) % 2];
-        self.marching_cubes
-            .extract_with_normals(nbuf, &mut vertices, &mut indices);
+        if self.backend.extract_surface(&mut vertices, self.frame_count) {
+            // The triangles don't share vertices
+            indices = (0..(vertices.len() / 6) as u32).collect();
+        } else {
+            self.extract_mesh_cpu(&mut vertices, &mut indices);
+        }
 
         // Offset on CPU based on the physical center of the scalar field
-        let offset = 1f32 - nbuf.inner().center();
-        // Re-normalize from [0, 1] to [-1, 1]
+        let offset = 1f32 - self.sources[self.frame_count % 2].inner().center();
+        // Re-normalize from [0, 1] to [-1, 1]
         // TODO: this would be efficient to do on the GPU => move to vertex shader
         vertices.chunks_mut(6).for_each(|chunk| {
             chunk[0] = 2f32 * (chunk[0] - offset);
@@ -142,13 +399,462 @@ impl GeometryGen {
             chunk[2] = 2f32 * (chunk[2] - offset);
         });
 
-        *vbo = VertexBuffer::dynamic(display, util::reinterpret_cast_slice(&vertices))
-            .expect("failed to create vertex buffer");
-        *ibo = IndexBuffer::dynamic(display, PrimitiveType::TrianglesList, &indices)
-            .expect("failed to create index buffer");
+        (vertices, indices)
+    }
+
+    /// Runs marching cubes on the host-side copy of the latest buffer. The reference for the
+    /// device and the fallback for backends that can't mesh. The vertices are in the [0, 1]
+    /// sample space.
+    pub fn extract_mesh_cpu(&mut self, vertices: &mut Vec<f32>, indices: &mut Vec<u32>) {
+        // Meshing on the host needs the mass of the latest step
+        self.sync_mass();
+
+        // Get the latest buffer
+        let nbuf = &self.sources[self.frame_count % 2];
+        self.marching_cubes
+            .extract_with_normals(nbuf, vertices, indices);
+    }
+
+    /// Extracts the current surface and writes it into a file at `path`.
+    pub fn export_surface<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        format: MeshFormat,
+    ) -> io::Result<()> {
+        let (vertices, indices) = self.extract_mesh();
+        export::save_mesh(path, format, &vertices, &indices)
+    }
+
+    /// Writes the most recently simulated mass, flow and temperature volumes into a VTK file at
+    /// `path`.
+    pub fn export_volume<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.sync_all();
+        let latest = self.frame_count % 2;
+        export::save_vtk(
+            path,
+            self.frame_count,
+            self.dim,
+            Fields {
+                mass: self.sources[latest].inner().into_slice(),
+                flow: self.flows[latest].as_slice().unwrap(),
+                temp: self.temperatures[latest].as_slice().unwrap(),
+            },
+        )
+    }
+
+    /// Computes summary statistics over the most recently simulated buffers.
+    pub fn stats(&mut self) -> FieldStats {
+        self.sync_all();
+        let start = Instant::now();
+        let (ejected_mass, bytes) = self.backend.ejected_mass();
+        self.readback.add(bytes, start);
+        let latest = self.frame_count % 2;
+        let mass = self.sources[latest].inner().into_slice();
+        let temps = self.temperatures[latest].as_slice().unwrap();
+
+        FieldStats {
+            step: self.frame_count,
+            // Negative values are inside the surface
+            filled_cells: mass.iter().filter(|&&m| m < 0f32).count(),
+            // Sum of the mass normalized into {0, 1}, see `liquid_sim.cl`
+            total_mass: mass.iter().map(|&m| normf(m)).sum(),
+            mean_temperature: temps.iter().sum::<f32>() / temps.len() as f32,
+            max_temperature: temps.iter().cloned().fold(0f32, f32::max),
+            ejected_mass,
+        }
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    /// Captures the buffers that the next step reads. `cfg` should be the settings that this was
+    /// built with. Impactors in flight and the laser are not included.
+    pub fn snapshot(&mut self, cfg: &Settings) -> Snapshot {
+        self.sync_all();
+        let read = self.frame_count % 2;
+        Snapshot {
+            dim: self.dim,
+            frame_count: self.frame_count,
+            seed: cfg.seed,
+            settings: cfg.clone(),
+            mass: self.sources[read].inner().into_slice().to_vec(),
+            flow: self.flows[read].as_slice().unwrap().to_vec(),
+            temp: self.temperatures[read].as_slice().unwrap().to_vec(),
+        }
+    }
+
+    /// Sets the laser origin and direction in the [-1, 1] model space, eg. from the camera.
+    pub fn aim_laser(&mut self, origin: Vector3f, dir: Vector3f) {
+        self.laser_ray = (origin, dir.normalize());
+    }
+
+    /// Walks the laser ray through the grid and returns a beam that starts where the ray hits
+    /// the surface, or `None` if it misses the planet.
+    fn laser_beam(&self) -> Option<HeatBeam> {
+        let (origin, dir) = self.laser_ray;
+        // The model-to-grid mapping is a uniform scale, the direction stays the same
+        let origin = self.model_to_grid(origin);
+        let mass = &self.traced_mass;
+        let dim = self.dim;
+
+        GridRay::new(origin, dir, dim)?
+            // Negative values are inside the surface
+            .find(|&(x, y, z)| mass[z * dim * dim + y * dim + x] < 0f32)
+            .map(|(x, y, z)| HeatBeam {
+                // Start from the near side of the hit cell
+                origin: Vector3f::new(x as f32, y as f32, z as f32) - dir * 0.5f32,
+                dir,
+                radius: LASER_RADIUS,
+                depth: LASER_DEPTH,
+                power: self.laser_strength,
+            })
+    }
+
+    /// Launches an impactor from `origin` along `dir`, both in the [-1, 1] model space.
+    pub fn launch_impactor(&mut self, origin: Vector3f, dir: Vector3f) {
+        let cells_per_unit = self.cells_per_model_unit();
+        let impactor = Impactor {
+            pos: self.model_to_grid(origin),
+            vel: dir.normalize() * self.impactor_speed * cells_per_unit,
+            mass: self.impactor_mass,
+            radius: self.impactor_radius * cells_per_unit,
+        };
+        debug!("launching impactor {:?}", impactor);
+        self.impactors.push(impactor);
+    }
+
+    /// Moves the impactors and deposits the ones that hit the surface into the buffers that are
+    /// read on this step.
+    fn update_impactors(&mut self, dt: f32) {
+        if self.impactors.is_empty() {
+            return;
+        }
+
+        self.update_traced_mass();
+        let dim = self.dim;
+        let g = gravity::cell_constant(self.gravity_constant, dim);
+        let boundary = self.boundary;
+        let mut hits = vec![];
+        {
+            let mass = &self.traced_mass;
+
+            // The same multipole approximation of gravity as in the simulation
+            let (_, coarse_dim) = gravity::blocks(dim);
+            let mut coarse = vec![0f32; coarse_dim * coarse_dim * coarse_dim * 4];
+            gravity::coarsen_mass(mass, dim, boundary, &mut coarse);
+
+            let mut flying = Vec::with_capacity(self.impactors.len());
+            for mut impactor in self.impactors.drain(..) {
+                let state = impactor.update(
+                    dt,
+                    dim,
+                    |pos| gravity::accel_at(pos, &coarse, dim, boundary, g),
+                    // Negative values are inside the surface
+                    |x, y, z| mass[z * dim * dim + y * dim + x] < 0f32,
+                );
+                match state {
+                    ImpactorState::Flying => flying.push(impactor),
+                    ImpactorState::Hit(cell) => hits.push((impactor, cell)),
+                    ImpactorState::Lost => debug!("impactor missed the planet"),
+                }
+            }
+            self.impactors = flying;
+        }
+
+        if hits.is_empty() {
+            return;
+        }
+        self.sync_all();
+        for (impactor, cell) in hits {
+            debug!("impactor hit the surface at {:?}", cell);
+            self.deposit_impactor(&impactor, cell, dt);
+        }
+
+        let read = self.frame_count % 2;
+        self.backend.upload(
+            Fields {
+                mass: self.sources[read].inner().into_slice(),
+                flow: self.flows[read].as_slice().unwrap(),
+                temp: self.temperatures[read].as_slice().unwrap(),
+            },
+            self.frame_count,
+        );
+        // The host-side buffers are up to date, trace against the deposited mass
+        self.refresh_traced_mass();
+    }
+
+    /// Adds the mass and momentum of the impactor into the buffers that are read on this step,
+    /// see `deposit_impactor`.
+    fn deposit_impactor(&mut self, impactor: &Impactor, hit: (usize, usize, usize), dt: f32) {
+        let read = self.frame_count % 2;
+        let mass = self.sources[read]
+            .inner_mut()
+            .elems_mut()
+            .as_slice_mut()
+            .unwrap();
+        let flow = self.flows[read].as_slice_mut().unwrap();
+        deposit_impactor(impactor, hit, self.dim, mass, flow, dt);
+    }
+
+    /// Number of cells per unit length in the [-1, 1] model space
+    fn cells_per_model_unit(&self) -> f32 {
+        0.5f32 * (self.dim - 1) as f32
+    }
+
+    /// Converts a position in the [-1, 1] model space into cell units, the inverse of the
+    /// mapping in `extract_mesh`.
+    fn model_to_grid(&self, pos: Vector3f) -> Vector3f {
+        let offset = 1f32 - self.sources[0].inner().center();
+        let scale = (self.dim - 1) as f32;
+        (pos * 0.5f32 + Vector3f::new(offset, offset, offset)) * scale
     }
 
     pub fn explode(&mut self, set: bool) {
         self.laser = set;
     }
+
+    /// Recompiles the kernels of the simulation backend from their sources.
+    pub fn reload_kernels(&mut self) -> Result<(), String> {
+        self.backend.reload()
+    }
+
+    pub fn set_laser_strength(&mut self, strength: f32) {
+        self.laser_strength = strength;
+    }
+}
+
+/// The squared radius of the generated planet in the [0, 1] sample space, which is also about the
+/// raw mass at its center. The full cells of scenes and meshes hold as much mass.
+// p = 0.145f32 is a nice default for max-size
+const PLANET_RADIUS: f32 = 0.1f32;
+
+/// Builds the initial field: a voxelized mesh, a scene or the generated planet, in that order of
+/// preference. Falls back to the next option if a file can't be loaded.
+fn initial_field(cfg: &Settings, dim: usize) -> ScalarField {
+    if !cfg.mesh.is_empty() {
+        match MeshSdf::load(&cfg.mesh) {
+            Ok(mesh) => {
+                info!("voxelizing the mesh {}", cfg.mesh);
+                return ScalarField::from_sdf(dim, &mesh, PLANET_RADIUS);
+            }
+            Err(e) => warn!("cannot load mesh {}: {}", cfg.mesh, e),
+        }
+    }
+
+    if !cfg.scene.is_empty() {
+        match Shape::load(&cfg.scene) {
+            Ok(scene) => {
+                info!("building the scene {}", cfg.scene);
+                return ScalarField::from_sdf(dim, &*scene.build(), PLANET_RADIUS);
+            }
+            Err(e) => warn!("cannot load scene {}: {}", cfg.scene, e),
+        }
+    }
+
+    let terrain = TerrainParams {
+        amplitude: cfg.terrain_amplitude,
+        frequency: cfg.terrain_frequency,
+        octaves: cfg.terrain_octaves,
+        craters: cfg.terrain_craters,
+    };
+    info!("building the planet with seed {}", cfg.seed);
+    ScalarField::new(dim, PLANET_RADIUS, &terrain, cfg.seed)
+}
+
+/// Spreads the mass of the impactor evenly into the cells within its radius from the hit cell.
+/// A cell holds at most one unit of normalized mass, so what doesn't fit is pushed outward into
+/// the nearest cells that still have room, one shell of cells around the hit at a time. The flow
+/// of each cell carries its added mass along the velocity of the impactor over one step.
+fn deposit_impactor(
+    impactor: &Impactor,
+    hit: (usize, usize, usize),
+    dim: usize,
+    mass: &mut [f32],
+    flow: &mut [f32],
+    dt: f32,
+) {
+    let vel = impactor.vel;
+    if !(impactor.mass.is_finite() && vel.x.is_finite() && vel.y.is_finite() && vel.z.is_finite())
+    {
+        warn!("ignoring an impactor with a non-finite mass or velocity");
+        return;
+    }
+    let radius = if impactor.radius.is_finite() {
+        impactor.radius.max(0.5f32)
+    } else {
+        0.5f32
+    };
+    let hit_pos = Vector3f::new(hit.0 as f32, hit.1 as f32, hit.2 as f32);
+    let gid = |x: usize, y: usize, z: usize| z * dim * dim + y * dim + x;
+
+    let mut within = vec![];
+    for r in 0..radius.ceil() as usize + 1 {
+        for_each_in_shell(hit, r, dim, |x, y, z| {
+            let pos = Vector3f::new(x as f32, y as f32, z as f32);
+            if (pos - hit_pos).magnitude() <= radius {
+                within.push(gid(x, y, z));
+            }
+        });
+    }
+    if within.is_empty() {
+        return;
+    }
+
+    let mut add = |gid: usize, amount: f32| -> f32 {
+        let m = normf(mass[gid]);
+        let added = amount.min((1f32 - m).max(0f32));
+        if added > 0f32 {
+            mass[gid] = denormf(m + added);
+            let momentum = vel * added * dt;
+            flow[gid * FLOW_STRIDE] += momentum.x;
+            flow[gid * FLOW_STRIDE + 1] += momentum.y;
+            flow[gid * FLOW_STRIDE + 2] += momentum.z;
+        }
+        added
+    };
+    let share = impactor.mass / within.len() as f32;
+    let mut left = impactor.mass;
+    for &gid in &within {
+        left -= add(gid, share);
+    }
+    // The shells up to the distance `dim` cover the grid from any hit
+    let mut r = 0;
+    while left > 0f32 && r < dim {
+        for_each_in_shell(hit, r, dim, |x, y, z| {
+            if left > 0f32 {
+                left -= add(gid(x, y, z), left);
+            }
+        });
+        r += 1;
+    }
+    if left > 0f32 {
+        warn!("the grid is full, {} of the impactor's mass was lost", left);
+    }
+}
+
+/// Calls `f` with the coordinates of each cell at the Chebyshev distance `r` from `center`, ie. on
+/// the faces of the box with a half-size of `r` cells around it. Edge-cells are overwritten by the
+/// boundary conditions and skipped.
+fn for_each_in_shell<F>(center: (usize, usize, usize), r: usize, dim: usize, mut f: F)
+where
+    F: FnMut(usize, usize, usize),
+{
+    let r = r as isize;
+    let (cx, cy, cz) = (center.0 as isize, center.1 as isize, center.2 as isize);
+    let inside = |v: isize| v >= 1 && v < dim as isize - 1;
+    for z in (cz - r..cz + r + 1).filter(|&z| inside(z)) {
+        for y in (cy - r..cy + r + 1).filter(|&y| inside(y)) {
+            let mut visit = |x: isize| {
+                if inside(x) {
+                    f(x as usize, y as usize, z as usize);
+                }
+            };
+            if (z - cz).abs() == r || (y - cy).abs() == r {
+                for x in cx - r..cx + r + 1 {
+                    visit(x);
+                }
+            } else {
+                visit(cx - r);
+                visit(cx + r);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_builds_the_same_field() {
+        let mut cfg = Settings::default();
+        cfg.seed = 1234;
+        let bits = |field: &ScalarField| -> Vec<u32> {
+            field.into_slice().iter().map(|v| v.to_bits()).collect()
+        };
+        let first = bits(&initial_field(&cfg, 32));
+        assert_eq!(first, bits(&initial_field(&cfg, 32)));
+
+        cfg.seed = 1235;
+        assert!(first != bits(&initial_field(&cfg, 32)));
+    }
+
+    #[test]
+    fn scenes_are_as_dense_as_the_planet() {
+        let dim = 32;
+        let deepest = |cfg: &Settings| -> f32 {
+            let field = initial_field(cfg, dim);
+            field.into_slice().iter().cloned().fold(0f32, f32::min)
+        };
+        let mut cfg = Settings::default();
+        cfg.scene = "data/scene/moon.yaml".to_string();
+        assert_eq!(deepest(&cfg), -PLANET_RADIUS);
+        // The terrain displaces the surface of the planet by a few tenths of its radius
+        let planet = deepest(&Settings::default());
+        assert!(planet < -0.5f32 * PLANET_RADIUS && planet > -2f32 * PLANET_RADIUS);
+    }
+
+    #[test]
+    fn impactor_adds_all_of_its_mass() {
+        let dim = 16;
+        let mut mass = initial_field(&Settings::default(), dim).into_slice().to_vec();
+        let mut flow = vec![0f32; dim * dim * dim * FLOW_STRIDE];
+        let total = |mass: &[f32]| -> f64 { mass.iter().map(|&m| normf(m) as f64).sum() };
+        let before = total(&mass);
+
+        // The topmost cell inside the planet, the cells below it are mostly full
+        let z = (0..dim)
+            .rev()
+            .find(|&z| mass[z * dim * dim + 8 * dim + 8] < 0f32)
+            .unwrap();
+        let hit = (8, 8, z);
+        let impactor = Impactor {
+            pos: Vector3f::new(8f32, 8f32, z as f32),
+            vel: Vector3f::new(0f32, 0f32, -1f32),
+            mass: 8f32,
+            radius: 1.5f32,
+        };
+        deposit_impactor(&impactor, hit, dim, &mut mass, &mut flow, 1f32 / 60f32);
+
+        let added = total(&mass) - before;
+        assert!((added - impactor.mass as f64).abs() < 1e-3, "added {}", added);
+        assert!(mass.iter().all(|&m| normf(m) <= 1f32 + 1e-6));
+    }
+
+    #[test]
+    fn shells_cover_the_interior_once() {
+        let dim = 9;
+        let mut visits = vec![0; dim * dim * dim];
+        for r in 0..dim {
+            for_each_in_shell((2, 6, 1), r, dim, |x, y, z| {
+                visits[z * dim * dim + y * dim + x] += 1
+            });
+        }
+        for z in 0..dim {
+            for y in 0..dim {
+                for x in 0..dim {
+                    let interior = [x, y, z].iter().all(|&v| v >= 1 && v < dim - 1);
+                    let expected = if interior { 1 } else { 0 };
+                    assert_eq!(visits[z * dim * dim + y * dim + x], expected, "{:?}", (x, y, z));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn non_finite_impactor_is_ignored() {
+        let dim = 8;
+        let mut mass = vec![denormf(0f32); dim * dim * dim];
+        let mut flow = vec![0f32; dim * dim * dim * FLOW_STRIDE];
+        let impactor = Impactor {
+            pos: Vector3f::new(4f32, 4f32, 4f32),
+            vel: Vector3f::new(0f32, ::std::f32::NAN, 0f32),
+            mass: 1f32,
+            radius: 1f32,
+        };
+        deposit_impactor(&impactor, (4, 4, 4), dim, &mut mass, &mut flow, 1f32 / 60f32);
+        assert!(mass.iter().all(|&m| m == denormf(0f32)));
+        assert!(flow.iter().all(|&f| f == 0f32));
+    }
 }
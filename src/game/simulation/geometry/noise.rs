@@ -0,0 +1,106 @@
+use prelude::*;
+use rand::Rng;
+
+/// Ken Perlin's improved gradient noise with a shuffled permutation table.
+pub struct Perlin {
+    // The permutation table repeated twice to avoid wrapping the indices
+    perm: Vec<usize>,
+}
+
+impl Perlin {
+    pub fn new<R: Rng>(rng: &mut R) -> Perlin {
+        let mut perm: Vec<usize> = (0..256).collect();
+        rng.shuffle(&mut perm);
+        let repeat = perm.clone();
+        perm.extend(repeat);
+        Perlin { perm }
+    }
+
+    /// Samples the noise at `p`, in range [-1, 1].
+    pub fn noise(&self, p: Vector3f) -> f32 {
+        let (xf, yf, zf) = (p.x.floor(), p.y.floor(), p.z.floor());
+        // Unit cube that contains the point
+        let xi = (xf as i32 & 255) as usize;
+        let yi = (yf as i32 & 255) as usize;
+        let zi = (zf as i32 & 255) as usize;
+        // Relative position of the point in the cube
+        let (x, y, z) = (p.x - xf, p.y - yf, p.z - zf);
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+
+        let perm = &self.perm;
+        let a = perm[xi] + yi;
+        let aa = perm[a] + zi;
+        let ab = perm[a + 1] + zi;
+        let b = perm[xi + 1] + yi;
+        let ba = perm[b] + zi;
+        let bb = perm[b + 1] + zi;
+
+        lerp(
+            w,
+            lerp(
+                v,
+                lerp(u, grad(perm[aa], x, y, z), grad(perm[ba], x - 1f32, y, z)),
+                lerp(
+                    u,
+                    grad(perm[ab], x, y - 1f32, z),
+                    grad(perm[bb], x - 1f32, y - 1f32, z),
+                ),
+            ),
+            lerp(
+                v,
+                lerp(
+                    u,
+                    grad(perm[aa + 1], x, y, z - 1f32),
+                    grad(perm[ba + 1], x - 1f32, y, z - 1f32),
+                ),
+                lerp(
+                    u,
+                    grad(perm[ab + 1], x, y - 1f32, z - 1f32),
+                    grad(perm[bb + 1], x - 1f32, y - 1f32, z - 1f32),
+                ),
+            ),
+        )
+    }
+
+    /// Fractal sum of `octaves` layers of noise, each with double the frequency and half the
+    /// amplitude of the previous one. Normalized into [-1, 1].
+    pub fn fbm(&self, p: Vector3f, octaves: u32) -> f32 {
+        let mut sum = 0f32;
+        let mut amplitude = 1f32;
+        let mut frequency = 1f32;
+        let mut total = 0f32;
+        for _ in 0..octaves {
+            sum += self.noise(p * frequency) * amplitude;
+            total += amplitude;
+            amplitude *= 0.5f32;
+            frequency *= 2f32;
+        }
+        if total > 0f32 {
+            sum / total
+        } else {
+            0f32
+        }
+    }
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6f32 - 15f32) + 10f32)
+}
+
+fn lerp(t: f32, a: f32, b: f32) -> f32 {
+    a + t * (b - a)
+}
+
+/// Dot product of the distance vector with one of 12 gradient directions selected by `hash`.
+fn grad(hash: usize, x: f32, y: f32, z: f32) -> f32 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
@@ -1,13 +1,15 @@
 use isosurface::source::Source;
 use prelude::*;
 use cgmath::{InnerSpace, Vector3};
-use rand;
-use rand::Rng;
+use rand::SeedableRng;
+use rand::chacha::ChaChaRng;
+use super::terrain::*;
 use ndarray::prelude::*;
 use std::cell::RefCell;
-use std::cmp::min;
 
-/// Represents a 3D scalar field in range [0..1]
+/// Represents a 3D scalar field in range [0..1]. The cells hold the raw mass, negative inside the
+/// surface and zero on it. Its scale depends on how the field was built, see `new` and
+/// `from_sdf`.
 #[derive(Clone)]
 pub struct ScalarField {
     elems: Array3<f32>,
@@ -15,15 +17,21 @@ pub struct ScalarField {
 }
 
 impl ScalarField {
-    pub fn new(dim: usize, threshold: f32, surface_jitter: f32) -> ScalarField {
-        // The center is 0.5 * (dim - 1); use that as "absolute center"
+    /// Builds a planet whose surface is displaced by procedural terrain. `threshold` is the
+    /// squared radius of the undisplaced sphere. The same `seed` always produces the same field.
+    /// The cells hold the squared distance from the center minus the squared surface radius, both
+    /// in the [0, 1] sample space, so the deepest cell holds about `-threshold`.
+    pub fn new(dim: usize, threshold: f32, terrain: &TerrainParams, seed: u64) -> ScalarField {
+        // The samples are at the cell centers, so the center of the field is at 0.5 * (dim - 1)
         let center_abs = Vector3f::new(
-            0.5f32 * (dim - 2) as f32,
-            0.5f32 * (dim - 2) as f32,
-            0.5f32 * (dim - 2) as f32,
+            0.5f32 * (dim - 1) as f32,
+            0.5f32 * (dim - 1) as f32,
+            0.5f32 * (dim - 1) as f32,
         );
 
-        let mut rng = rand::thread_rng();
+        let mut rng = seeded_rng(seed);
+        let terrain = Terrain::new(terrain, &mut rng);
+        let radius = threshold.sqrt();
 
         let mut elems = unsafe { Array::uninitialized((dim, dim, dim)) };
         for z in 0..dim {
@@ -31,11 +39,17 @@ impl ScalarField {
                 for x in 0..dim {
                     let pos = Vector3f::new(x as f32, y as f32, z as f32);
                     // Distance between this point and the simulation center; sample-limits = [(0, 0, 0), (1, 1, 1)]
-                    let dist_norm = (pos - center_abs) / dim as f32;
+                    let dist_norm = (pos - center_abs) / (dim - 1) as f32;
                     let dist_norm2 = dist_norm.magnitude2();
-                    let jitter = (rng.next_f32() * 2f32 - 1f32) * surface_jitter;
+                    // Displace the surface radially by the terrain height
+                    let height = if dist_norm2 > 0f32 {
+                        terrain.height(dist_norm.normalize())
+                    } else {
+                        0f32
+                    };
+                    let surface = radius * (1f32 + height);
                     let mut elem = unsafe { elems.uget_mut((z, y, x)) };
-                    *elem = dist_norm2 - threshold + jitter;
+                    *elem = dist_norm2 - surface * surface;
                     /*
                     if dist_norm2 > threshold * threshold {
                         *elem = 1f32;
@@ -52,13 +66,38 @@ impl ScalarField {
             dim: dim as f32,
         }
     }
+    /// Rasterises a signed distance field in the [-1, 1] model space into the cells. The
+    /// distances are scaled into cell units, clamped into [-1, 1] and scaled by `depth`, so that
+    /// the cells deeper than one cell hold `-depth` and a cell on the surface holds the fraction
+    /// of it that is inside. The depth of the generated planet is its `threshold`, see `new`.
+    pub fn from_sdf(dim: usize, sdf: &Source, depth: f32) -> ScalarField {
+        let cells_per_unit = 0.5f32 * (dim - 1) as f32;
+        let mut elems = unsafe { Array::uninitialized((dim, dim, dim)) };
+        for ((z, y, x), elem) in elems.indexed_iter_mut() {
+            let model = |i: usize| i as f32 / cells_per_unit - 1f32;
+            let d = sdf.sample(model(x), model(y), model(z));
+            *elem = (d * cells_per_unit).max(-1f32).min(1f32) * depth;
+        }
+
+        ScalarField {
+            elems,
+            dim: dim as f32,
+        }
+    }
+    /// Wraps the raw cells of a `dim`^3 grid, eg. from a snapshot.
+    pub fn from_cells(dim: usize, cells: Vec<f32>) -> ScalarField {
+        ScalarField {
+            elems: Array::from_shape_vec((dim, dim, dim), cells)
+                .expect("the number of cells doesn't match the dim"),
+            dim: dim as f32,
+        }
+    }
     pub fn dim(&self) -> usize {
         self.dim as usize
     }
+    /// The center of the field in the [0, 1] sample space
     pub fn center(&self) -> f32 {
-        // HACK: This should be dim-1, not dim-2 but hacking it like this compensates for the error
-        // caused by discretization in the marching cubes algorithm
-        0.5f32 * (self.dim - 2f32) / self.dim
+        0.5f32
     }
     pub fn elems_mut(&mut self) -> &mut Array3<f32> {
         &mut self.elems
@@ -66,43 +105,136 @@ impl ScalarField {
     pub fn into_slice(&self) -> &[f32] {
         self.elems.view().into_slice().unwrap()
     }
-    // TODO: this indexing stuff needs some work
-    pub fn elem(&self, v: &Vector3f) -> f32 {
-        let idx = self.resolve_index3d(v);
-        unsafe { *self.elems.uget(idx) }
-    }
-    pub fn elem_mut(&mut self, v: &Vector3f) -> &mut f32 {
-        let idx = self.resolve_index3d(v);
-        unsafe { self.elems.uget_mut(idx) }
-    }
-    fn resolve_index(&self, v: &Vector3f) -> usize {
-        let x_idx = (self.dim * v[0]) as usize;
-        let y_idx = (self.dim * v[1]) as usize;
-        let z_idx = (self.dim * v[2]) as usize;
-
-        // HACK: min() is a workaround to overindexing
-        let d = self.dim as usize;
-        ((d * d * z_idx + d * y_idx + x_idx) as usize).min(d * d * d - 1)
-    }
-    fn resolve_index3d(&self, v: &Vector3f) -> (usize, usize, usize) {
-        // Re-scale the indexer vector from [0, 1] to [0, 1[.
-        // HACK: This should be dim-1, not dim-2 but hacking it like this compensates for the error
-        // caused by discretization in the marching cubes algorithm; this also causes a crash in
-        // some fairly rare scenarios :-D
-        // HACK: yeah, let's just clamp them so there's no crash when normals start exploding
-        let d = self.dim as usize - 1;
-        let z_idx = min(((self.dim - 2f32) * v[2]) as usize, d);
-        let y_idx = min(((self.dim - 2f32) * v[1]) as usize, d);
-        let x_idx = min(((self.dim - 2f32) * v[0]) as usize, d);
-
-        (z_idx, y_idx, x_idx)
+    /// Distance between two neighboring cells in the [0, 1] sample space
+    pub fn cell_dist(&self) -> f32 {
+        1f32 / (self.dim - 1f32)
+    }
+    fn elem(&self, x: usize, y: usize, z: usize) -> f32 {
+        unsafe { *self.elems.uget((z, y, x)) }
     }
 }
 
+/// Creates a platform-independent random number generator from a seed.
+pub fn seeded_rng(seed: u64) -> ChaChaRng {
+    ChaChaRng::from_seed(&[seed as u32, (seed >> 32) as u32])
+}
+
 impl Source for ScalarField {
+    /// Trilinearly interpolates between the cells. The first and the last cell on each axis are
+    /// centered at 0 and 1, samples outside of [0, 1] are clamped to the border.
     fn sample(&self, x: f32, y: f32, z: f32) -> f32 {
-        // TODO: improve algorithm, this should likely be like trilinear 2D-texture sampling that
-        // would allow interpolation if the render resolution was higher than model resolution
-        self.elem(&Vector3f::new(x, y, z))
+        let last = self.dim as usize - 1;
+        // Index of the lower corner cell and the relative position between it and the next cell
+        let split = |v: f32| {
+            let p = (v * last as f32).max(0f32).min(last as f32);
+            let i = (p.floor() as usize).min(last.saturating_sub(1));
+            (i, (i + 1).min(last), p - i as f32)
+        };
+        let (x0, x1, tx) = split(x);
+        let (y0, y1, ty) = split(y);
+        let (z0, z1, tz) = split(z);
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+
+        let c00 = lerp(self.elem(x0, y0, z0), self.elem(x1, y0, z0), tx);
+        let c10 = lerp(self.elem(x0, y1, z0), self.elem(x1, y1, z0), tx);
+        let c01 = lerp(self.elem(x0, y0, z1), self.elem(x1, y0, z1), tx);
+        let c11 = lerp(self.elem(x0, y1, z1), self.elem(x1, y1, z1), tx);
+        lerp(lerp(c00, c10, ty), lerp(c01, c11, ty), tz)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIM: usize = 17;
+
+    /// A linear field in the [-1, 1] model space, small enough not to be clamped in cell units
+    struct Linear;
+
+    impl Source for Linear {
+        fn sample(&self, x: f32, y: f32, z: f32) -> f32 {
+            (x + 2f32 * y - z) * 0.02f32
+        }
+    }
+
+    /// The distance to a sphere at the origin, scaled down so that it isn't clamped anywhere in
+    /// the grid
+    struct ScaledSphere;
+
+    const RADIUS: f32 = 0.6f32;
+    const SCALE: f32 = 0.1f32;
+
+    impl Source for ScaledSphere {
+        fn sample(&self, x: f32, y: f32, z: f32) -> f32 {
+            (Vector3f::new(x, y, z).magnitude() - RADIUS) * SCALE
+        }
+    }
+
+    /// Off-grid points that cover the [0, 1] sample space evenly
+    fn points() -> Vec<Vector3f> {
+        (0..500)
+            .map(|i| {
+                let f = |k: f32| ((i as f32 + 0.5f32) * k).fract();
+                Vector3f::new(f(0.8191725f32), f(0.6710436f32), f(0.5497005f32))
+            })
+            .collect()
+    }
+
+    fn to_model(p: Vector3f) -> Vector3f {
+        p * 2f32 - Vector3f::new(1f32, 1f32, 1f32)
+    }
+
+    #[test]
+    fn samples_at_the_cells_are_the_cells() {
+        let field = ScalarField::from_sdf(DIM, &Linear, 1f32);
+        let last = (DIM - 1) as f32;
+        for &(x, y, z) in &[(0, 0, 0), (3, 7, 11), (DIM - 1, 5, DIM - 1), (16, 16, 16)] {
+            let sampled = field.sample(x as f32 / last, y as f32 / last, z as f32 / last);
+            assert_eq!(sampled, field.elem(x, y, z));
+        }
+    }
+
+    #[test]
+    fn trilinear_is_exact_for_linear_fields() {
+        let field = ScalarField::from_sdf(DIM, &Linear, 1f32);
+        let cells_per_unit = 0.5f32 * (DIM - 1) as f32;
+        for p in points() {
+            let model = to_model(p);
+            let expected = Linear.sample(model.x, model.y, model.z) * cells_per_unit;
+            let sampled = field.sample(p.x, p.y, p.z);
+            assert!(
+                (sampled - expected).abs() < 1e-5,
+                "{:?}: {} != {}",
+                p,
+                sampled,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn trilinear_follows_a_sphere() {
+        let field = ScalarField::from_sdf(DIM, &ScaledSphere, 1f32);
+        let cells_per_unit = 0.5f32 * (DIM - 1) as f32;
+        // The error grows with the curvature, which is at most 1 / (distance - 1 cell) at the
+        // corners around a point. Stay away from the center where it blows up.
+        let min_dist = 0.5f32;
+        let tolerance = SCALE * 3f32 / (8f32 * (min_dist * cells_per_unit - 1f32));
+        for p in points() {
+            let model = to_model(p);
+            if model.magnitude() < min_dist {
+                continue;
+            }
+            let expected = ScaledSphere.sample(model.x, model.y, model.z) * cells_per_unit;
+            let sampled = field.sample(p.x, p.y, p.z);
+            assert!(
+                (sampled - expected).abs() < tolerance,
+                "{:?}: {} != {}",
+                p,
+                sampled,
+                expected
+            );
+        }
     }
 }
@@ -0,0 +1,165 @@
+use isosurface::source::Source;
+use prelude::*;
+use serde_yaml;
+use std::fs;
+use std::io;
+use std::path::Path;
+use super::csg::*;
+use super::sphere::Sphere;
+use super::torus::Torus;
+
+/// A node in the YAML description of the initial bodies. Distances are in the [-1, 1] model
+/// space, eg.
+///
+/// ```yaml
+/// subtraction:
+///   base:
+///     sphere: { radius: 0.5 }
+///   cut:
+///     - translate:
+///         offset: [0.3, 0.3, 0.35]
+///         shape:
+///           sphere: { radius: 0.15 }
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Shape {
+    Sphere {
+        radius: f32,
+    },
+    /// A torus around the z-axis
+    Torus {
+        major_radius: f32,
+        minor_radius: f32,
+    },
+    Union(Vec<Shape>),
+    Intersection(Vec<Shape>),
+    /// Cuts all shapes in `cut` out of `base`
+    Subtraction {
+        base: Box<Shape>,
+        cut: Vec<Shape>,
+    },
+    SmoothUnion {
+        k: f32,
+        shapes: Vec<Shape>,
+    },
+    Translate {
+        offset: [f32; 3],
+        shape: Box<Shape>,
+    },
+    Scale {
+        factor: f32,
+        shape: Box<Shape>,
+    },
+}
+
+impl Shape {
+    /// Reads a scene from a YAML file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Shape> {
+        let f = fs::File::open(path)?;
+        let shape: Shape =
+            serde_yaml::from_reader(f).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        shape
+            .validate()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(shape)
+    }
+
+    /// Rejects the values that don't build a distance field, eg. a scale by zero.
+    fn validate(&self) -> Result<(), String> {
+        match *self {
+            Shape::Sphere { .. } | Shape::Torus { .. } => Ok(()),
+            Shape::Union(ref shapes)
+            | Shape::Intersection(ref shapes)
+            | Shape::SmoothUnion { ref shapes, .. } => {
+                shapes.iter().map(|shape| shape.validate()).collect()
+            }
+            Shape::Subtraction { ref base, ref cut } => {
+                base.validate()?;
+                cut.iter().map(|shape| shape.validate()).collect()
+            }
+            Shape::Translate { ref shape, .. } => shape.validate(),
+            Shape::Scale { factor, ref shape } => {
+                if !(factor > 0f32 && factor.is_finite()) {
+                    return Err(format!("scale factor must be positive, got {}", factor));
+                }
+                shape.validate()
+            }
+        }
+    }
+
+    /// Builds the signed distance field of the scene.
+    pub fn build(&self) -> Box<Source> {
+        match *self {
+            Shape::Sphere { radius } => Box::new(Sphere { radius }),
+            Shape::Torus {
+                major_radius,
+                minor_radius,
+            } => Box::new(Torus {
+                major_radius,
+                minor_radius,
+            }),
+            Shape::Union(ref shapes) => {
+                fold(shapes, |a, b| Box::new(Union { a, b }))
+            }
+            Shape::Intersection(ref shapes) => {
+                fold(shapes, |a, b| Box::new(Intersection { a, b }))
+            }
+            Shape::Subtraction { ref base, ref cut } => {
+                cut.iter().fold(base.build(), |a, shape| {
+                    Box::new(Subtraction { a, b: shape.build() })
+                })
+            }
+            Shape::SmoothUnion { k, ref shapes } => {
+                fold(shapes, |a, b| Box::new(SmoothUnion { a, b, k }))
+            }
+            Shape::Translate { offset, ref shape } => Box::new(Translate {
+                inner: shape.build(),
+                offset: Vector3f::new(offset[0], offset[1], offset[2]),
+            }),
+            Shape::Scale { factor, ref shape } => Box::new(Scale {
+                inner: shape.build(),
+                factor,
+            }),
+        }
+    }
+}
+
+/// Combines the shapes pairwise from left to right. An empty list is empty space.
+fn fold<F>(shapes: &[Shape], combine: F) -> Box<Source>
+where
+    F: Fn(Box<Source>, Box<Source>) -> Box<Source>,
+{
+    let mut iter = shapes.iter().map(|shape| shape.build());
+    match iter.next() {
+        Some(first) => iter.fold(first, combine),
+        None => Box::new(Empty {}),
+    }
+}
+
+struct Empty {}
+
+impl Source for Empty {
+    fn sample(&self, _x: f32, _y: f32, _z: f32) -> f32 {
+        ::std::f32::MAX
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_by_zero_is_rejected() {
+        let scene = |factor: &str| -> Shape {
+            let yaml = format!(
+                "scale: {{ factor: {}, shape: {{ sphere: {{ radius: 0.5 }} }} }}",
+                factor
+            );
+            serde_yaml::from_str(&yaml).unwrap()
+        };
+        assert!(scene("0.5").validate().is_ok());
+        assert!(scene("0.0").validate().is_err());
+        assert!(scene("-2.0").validate().is_err());
+    }
+}
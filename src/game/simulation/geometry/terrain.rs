@@ -0,0 +1,108 @@
+use prelude::*;
+use cgmath::InnerSpace;
+use rand::Rng;
+use super::noise::Perlin;
+
+/// Parameters for the procedural terrain of the initial planet.
+#[derive(Clone, Debug)]
+pub struct TerrainParams {
+    /// Height of the terrain relative to the planet radius
+    pub amplitude: f32,
+    /// Frequency of the lowest noise octave over the unit sphere
+    pub frequency: f32,
+    pub octaves: u32,
+    pub craters: usize,
+}
+
+struct Crater {
+    center: Vector3f,
+    // Angular radius of the bowl
+    radius: f32,
+}
+
+/// A radial height map for the planet surface: noise continents with mountain ridges, and
+/// craters.
+pub struct Terrain {
+    params: TerrainParams,
+    continents: Perlin,
+    mountains: Perlin,
+    craters: Vec<Crater>,
+}
+
+impl Terrain {
+    pub fn new<R: Rng>(params: &TerrainParams, rng: &mut R) -> Terrain {
+        let continents = Perlin::new(rng);
+        let mountains = Perlin::new(rng);
+        let craters = (0..params.craters)
+            .map(|_| {
+                let center = Vector3f::new(
+                    rng.next_f32() * 2f32 - 1f32,
+                    rng.next_f32() * 2f32 - 1f32,
+                    rng.next_f32() * 2f32 - 1f32,
+                );
+                Crater {
+                    center: if center.magnitude2() > 0f32 {
+                        center.normalize()
+                    } else {
+                        Vector3f::unit_y()
+                    },
+                    radius: 0.1f32 + rng.next_f32() * 0.25f32,
+                }
+            })
+            .collect();
+
+        Terrain {
+            params: params.clone(),
+            continents,
+            mountains,
+            craters,
+        }
+    }
+
+    /// Height of the surface in the normalized direction `dir`, relative to the planet radius.
+    pub fn height(&self, dir: Vector3f) -> f32 {
+        let p = &self.params;
+        let continents = self.continents.fbm(dir * p.frequency, p.octaves);
+        // Ridged noise makes sharp mountain chains, only on land
+        let ridge = 1f32 - self.mountains.fbm(dir * p.frequency * 4f32, p.octaves).abs();
+        let mountains = continents.max(0f32) * ridge * ridge;
+
+        let craters: f32 = self.craters
+            .iter()
+            .map(|crater| {
+                let angle = dir.dot(crater.center).max(-1f32).min(1f32).acos();
+                crater_profile(angle / crater.radius)
+            })
+            .sum();
+
+        p.amplitude * (continents + mountains + craters)
+    }
+}
+
+/// Height of a crater at `x` crater radii from its center: a bowl that rises into a rim at the
+/// crater radius and falls off to the surrounding terrain at 1.5 radii.
+fn crater_profile(x: f32) -> f32 {
+    if x < 1f32 {
+        // Bowl, meets the top of the rim
+        0.7f32 * x * x - 0.5f32
+    } else if x < 1.5f32 {
+        // Rim
+        0.2f32 * (1f32 - (x - 1f32) / 0.5f32)
+    } else {
+        0f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crater_profile_is_continuous() {
+        let eps = 1e-4f32;
+        for &x in &[1f32, 1.5f32] {
+            assert!((crater_profile(x - eps) - crater_profile(x)).abs() < 1e-3);
+        }
+        assert_eq!(crater_profile(0f32), -0.5f32);
+    }
+}
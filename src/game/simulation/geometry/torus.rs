@@ -1,18 +1,21 @@
 use isosurface::source::Source;
 
-/// The distance-field equation for a torus
-pub fn torus(x: f32, y: f32, z: f32) -> f32 {
-    const R1: f32 = 1.0 / 4.0;
-    const R2: f32 = 1.0 / 10.0;
-    let q_x = ((x * x + y * y).sqrt()).abs() - R1;
+/// The distance-field equation for a torus around the z-axis, `r1` is the distance from the
+/// center of the torus to the center of the tube and `r2` is the radius of the tube
+pub fn torus(x: f32, y: f32, z: f32, r1: f32, r2: f32) -> f32 {
+    let q_x = ((x * x + y * y).sqrt()).abs() - r1;
     let len = (q_x * q_x + z * z).sqrt();
-    len - R2
+    len - r2
 }
 
-pub struct Torus {}
+/// A torus centered at the origin
+pub struct Torus {
+    pub major_radius: f32,
+    pub minor_radius: f32,
+}
 
 impl Source for Torus {
     fn sample(&self, x: f32, y: f32, z: f32) -> f32 {
-        torus(x - 0.5, y - 0.5, z - 0.5)
+        torus(x, y, z, self.major_radius, self.minor_radius)
     }
 }
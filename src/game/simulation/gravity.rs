@@ -0,0 +1,208 @@
+use rayon::prelude::*;
+use prelude::*;
+use cgmath::InnerSpace;
+use super::backend::*;
+
+/// Side length of the coarse grid that the mass is summed into for the gravity pass
+const COARSE_TARGET: usize = 8;
+/// Accelerations are clamped to this to keep the simulation stable, as the old central pull was
+pub const MAX_ACCEL: f32 = 10f32;
+
+/// Returns the block side length in cells and the side length of the coarse grid of blocks for
+/// the multipole approximation.
+pub fn blocks(dim: usize) -> (usize, usize) {
+    let block = (dim + COARSE_TARGET - 1) / COARSE_TARGET;
+    let coarse_dim = (dim + block - 1) / block;
+    (block, coarse_dim)
+}
+
+/// The gravitational constant in cell units. The mass of a cell is its density times its volume,
+/// so the same `gravity_constant` pulls about as hard at the surface at every `dim`.
+pub fn cell_constant(g: f32, dim: usize) -> f32 {
+    let dx = physics_cell_dist(dim);
+    g * dx * dx * dx
+}
+
+/// Amount of gravitating matter in a cell: the depth of the cell below the surface, clamped
+/// into [0, 1]. Cells outside the surface don't attract anything.
+pub fn density(mass: f32) -> f32 {
+    (-mass).max(0f32).min(1f32)
+}
+
+/// Sums the mass into blocks. Each block is stored as four floats: the center of mass in cell
+/// units and the total mass, see `coarsen_mass` in `cl/liquid_sim.cl`. The border cells are
+/// skipped unless the grid wraps around, as the boundary conditions overwrite them.
+pub fn coarsen_mass(mass: &[f32], dim: usize, boundary: Boundary, coarse: &mut [f32]) {
+    let is_edge = |c: usize| boundary != Boundary::Periodic && (c == 0 || c == dim - 1);
+    let (block, coarse_dim) = blocks(dim);
+    coarse
+        .par_chunks_mut(4)
+        .enumerate()
+        .for_each(|(bid, out)| {
+            let bz = bid / (coarse_dim * coarse_dim);
+            let by = (bid / coarse_dim) % coarse_dim;
+            let bx = bid % coarse_dim;
+            let mut weighted = Vector3f::new(0f32, 0f32, 0f32);
+            let mut total = 0f32;
+            for z in bz * block..((bz + 1) * block).min(dim) {
+                for y in by * block..((by + 1) * block).min(dim) {
+                    for x in bx * block..((bx + 1) * block).min(dim) {
+                        if is_edge(z) || is_edge(y) || is_edge(x) {
+                            continue;
+                        }
+                        let m = density(mass[z * dim * dim + y * dim + x]);
+                        weighted += Vector3f::new(x as f32, y as f32, z as f32) * m;
+                        total += m;
+                    }
+                }
+            }
+            let center = if total > 0f32 {
+                weighted / total
+            } else {
+                Vector3f::new(0f32, 0f32, 0f32)
+            };
+            out.copy_from_slice(&[center.x, center.y, center.z, total]);
+        });
+}
+
+/// The offset from `pos` to `target`. When the grid wraps around, it's the offset to the nearest
+/// periodic image of `target`, so mass across the border pulls the short way around.
+fn separation(target: Vector3f, pos: Vector3f, dim: usize, boundary: Boundary) -> Vector3f {
+    let diff = target - pos;
+    if boundary != Boundary::Periodic {
+        return diff;
+    }
+    let period = dim as f32;
+    let wrap = |d: f32| d - period * (d / period).round();
+    Vector3f::new(wrap(diff.x), wrap(diff.y), wrap(diff.z))
+}
+
+/// Gravity acceleration at `pos` from the coarse blocks, softened by one block so that the
+/// blocks close to `pos` don't blow up. With periodic boundaries only the nearest image of each
+/// block pulls.
+pub fn accel_at(
+    pos: Vector3f,
+    coarse: &[f32],
+    dim: usize,
+    boundary: Boundary,
+    g: f32,
+) -> Vector3f {
+    let (block, _) = blocks(dim);
+    let soft2 = (block * block) as f32;
+    let mut accel = Vector3f::new(0f32, 0f32, 0f32);
+    for b in coarse.chunks(4) {
+        if b[3] <= 0f32 {
+            continue;
+        }
+        let diff = separation(Vector3f::new(b[0], b[1], b[2]), pos, dim, boundary);
+        let d2 = diff.magnitude2() + soft2;
+        accel += diff * (g * b[3] / (d2 * d2.sqrt()));
+    }
+    Vector3f::new(
+        accel.x.max(-MAX_ACCEL).min(MAX_ACCEL),
+        accel.y.max(-MAX_ACCEL).min(MAX_ACCEL),
+        accel.z.max(-MAX_ACCEL).min(MAX_ACCEL),
+    )
+}
+
+/// Computes the per-cell gravity acceleration field as float3's padded to four floats, see
+/// `compute_gravity` in `cl/liquid_sim.cl`.
+pub fn compute_gravity(
+    coarse: &[f32],
+    dim: usize,
+    boundary: Boundary,
+    g: f32,
+    gravity: &mut [f32],
+) {
+    gravity
+        .par_chunks_mut(dim * dim * FLOW_STRIDE)
+        .enumerate()
+        .for_each(|(z, slice)| {
+            for y in 0..dim {
+                for x in 0..dim {
+                    let pos = Vector3f::new(x as f32, y as f32, z as f32);
+                    let a = accel_at(pos, coarse, dim, boundary, g);
+                    let local = (y * dim + x) * FLOW_STRIDE;
+                    slice[local..local + 3].copy_from_slice(&[a.x, a.y, a.z]);
+                }
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIM: usize = 16;
+
+    /// Empty cells with full boxes of cells between the inclusive corners
+    fn boxes(corners: &[((usize, usize, usize), (usize, usize, usize))]) -> Vec<f32> {
+        let mut mass = vec![1f32; DIM * DIM * DIM];
+        for &(lo, hi) in corners {
+            for z in lo.2..hi.2 + 1 {
+                for y in lo.1..hi.1 + 1 {
+                    for x in lo.0..hi.0 + 1 {
+                        mass[z * DIM * DIM + y * DIM + x] = -1f32;
+                    }
+                }
+            }
+        }
+        mass
+    }
+
+    fn coarse(mass: &[f32], boundary: Boundary) -> Vec<f32> {
+        let (_, coarse_dim) = blocks(DIM);
+        let mut coarse = vec![0f32; coarse_dim * coarse_dim * coarse_dim * 4];
+        coarsen_mass(mass, DIM, boundary, &mut coarse);
+        coarse
+    }
+
+    #[test]
+    fn separated_blobs_accelerate_toward_each_other() {
+        let mass = boxes(&[((2, 6, 6), (3, 9, 9)), ((12, 6, 6), (13, 9, 9))]);
+        let coarse = coarse(&mass, Boundary::Closed);
+        let g = cell_constant(250f32, DIM);
+        let left = accel_at(Vector3f::new(2.5, 7.5, 7.5), &coarse, DIM, Boundary::Closed, g);
+        let right = accel_at(Vector3f::new(12.5, 7.5, 7.5), &coarse, DIM, Boundary::Closed, g);
+        assert!(left.x > 0f32 && right.x < 0f32, "{:?} {:?}", left, right);
+        assert!((left.x + right.x).abs() < 1e-4 * left.x);
+        assert!(left.y.abs() < 1e-4 * left.x && left.z.abs() < 1e-4 * left.x);
+    }
+
+    #[test]
+    fn symmetric_ball_pulls_toward_its_center() {
+        let mut mass = vec![1f32; DIM * DIM * DIM];
+        let center = Vector3f::new(7.5, 7.5, 7.5);
+        for z in 0..DIM {
+            for y in 0..DIM {
+                for x in 0..DIM {
+                    let pos = Vector3f::new(x as f32, y as f32, z as f32);
+                    if (pos - center).magnitude() < 5f32 {
+                        mass[z * DIM * DIM + y * DIM + x] = -1f32;
+                    }
+                }
+            }
+        }
+        let coarse = coarse(&mass, Boundary::Closed);
+        let g = cell_constant(250f32, DIM);
+        let accel = |pos: Vector3f| accel_at(pos, &coarse, DIM, Boundary::Closed, g);
+
+        assert!(accel(center).magnitude() < 1e-5, "{:?}", accel(center));
+        let offset = Vector3f::new(4f32, -2f32, 1f32);
+        let (a, b) = (accel(center + offset), accel(center - offset));
+        assert!((a + b).magnitude() < 1e-4 * a.magnitude(), "{:?} {:?}", a, b);
+        assert!(a.dot(offset) < 0f32);
+    }
+
+    #[test]
+    fn periodic_blobs_attract_across_the_border() {
+        let mass = boxes(&[((1, 6, 6), (2, 9, 9)), ((13, 6, 6), (14, 9, 9))]);
+        let g = cell_constant(250f32, DIM);
+        let pos = Vector3f::new(1.5, 7.5, 7.5);
+        let closed = accel_at(pos, &coarse(&mass, Boundary::Closed), DIM, Boundary::Closed, g);
+        let periodic = coarse(&mass, Boundary::Periodic);
+        let wrapped = accel_at(pos, &periodic, DIM, Boundary::Periodic, g);
+        assert!(closed.x > 0f32);
+        assert!(wrapped.x < 0f32, "{:?}", wrapped);
+    }
+}
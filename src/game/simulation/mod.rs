@@ -1,168 +1,262 @@
+mod backend;
+mod cpu_liquid_sim;
+pub mod export;
 mod geometry;
+mod gravity;
 mod unit_cube;
 mod ocl_liquid_sim;
+mod ocl_marching_cubes;
+mod renderer;
+pub mod snapshot;
 
 use glium::*;
-use glium::texture::SrgbTexture2d;
 use cgmath::prelude::*;
 use cgmath::Deg;
-use std::str;
 use util::camera::*;
 use handle_events::*;
-use index::PrimitiveType;
-use util::*;
 use prelude::*;
-use shader;
+use self::export::MeshFormat;
 use self::geometry::*;
-use self::unit_cube::*;
+use self::ocl_liquid_sim::kernel_files;
+use self::renderer::*;
+use self::snapshot::Snapshot;
 use super::settings::*;
-use image;
-use image::GenericImage;
+use util::FileWatcher;
+
+pub use self::geometry::{FieldStats, GeometryGen, ReadbackStats};
+pub use self::renderer::{frame_path, Renderer};
+pub use self::ocl_liquid_sim::print_devices;
 
 pub struct Simulation {
-    program: Program,
+    renderer: Renderer,
     camera: RotationalCamera,
     cam_control: CameraControl,
     m_transform: Decomposedf,
     geom_gen: GeometryGen,
-    vbo: VertexBuffer<VertexPN>,
-    ibo: IndexBuffer<u32>,
-    cube_vbo: VertexBuffer<VertexPN>,
-    cube_ibo: IndexBuffer<u32>,
     cfg: Settings,
-    planet_texture: SrgbTexture2d,
-    polar_texture: SrgbTexture2d,
+    fixed_dt: f32,
+    // Number of frames drawn, for capturing every Nth
+    drawn_frames: usize,
+    // Watchers for the shaders and the kernel sources in dev mode
+    shader_watcher: Option<FileWatcher>,
+    kernel_watcher: Option<FileWatcher>,
 }
 
 impl Simulation {
     pub fn new(fixed_dt: f32, cfg: Settings, display: &mut Display) -> Simulation {
-        let program = program!(
-            display,
-            140 => {
-                vertex: str::from_utf8(include_bytes!("../../shader/project.140.vert")).unwrap(),
-                fragment: str::from_utf8(include_bytes!("../../shader/triplanar.140.frag")).unwrap(),
-            }).unwrap();
+        let renderer = Renderer::new(display, &cfg);
 
         let cam_control = CameraControl::default();
         let window_size = display.gl_window().get_inner_size().unwrap();
-        let camera = RotationalCamera::new(
-            Point3f::new(0.0, 0.0, 0.0),
-            Deg(0f32),
-            Deg(0f32),
-            1.5f32,
-            window_size.0 as f32 / window_size.1 as f32,
-        );
+        let camera = default_camera(window_size.0 as f32 / window_size.1 as f32);
 
-        let geom_gen = GeometryGen::new(cfg.scalar_field_dim, fixed_dt, cfg.laser_strength);
+        let geom_gen = GeometryGen::new(&cfg, fixed_dt);
 
-        let m_transform = Decomposedf {
-            scale: 1f32,
-            rot: Quaternionf::zero(),
-            disp: Vector3f::zero(),
+        let (shader_watcher, kernel_watcher) = if cfg.dev_mode {
+            info!("dev mode, reloading the shaders and the kernels on changes");
+            (
+                Some(FileWatcher::new(Renderer::shader_files())),
+                Some(FileWatcher::new(kernel_files())),
+            )
+        } else {
+            (None, None)
         };
 
-        // Load the textures for the triplanar mapping
-        let planet_texture = load_texture(&cfg.planet_texture, display);
-        let polar_texture = load_texture(&cfg.polar_texture, display);
-
         Simulation {
-            program,
+            renderer,
             cam_control,
-            m_transform,
+            m_transform: model_transform(),
             camera,
             geom_gen,
-            vbo: VertexBuffer::dynamic(display, &[]).unwrap(),
-            ibo: IndexBuffer::dynamic(display, PrimitiveType::TrianglesList, &[]).unwrap(),
-            cube_vbo: VertexBuffer::new(display, &UNIT_CUBE_VBO).unwrap(),
-            cube_ibo: IndexBuffer::new(display, PrimitiveType::TrianglesList, &UNIT_CUBE_IBO)
-                .unwrap(),
             cfg,
-            planet_texture,
-            polar_texture,
+            fixed_dt,
+            drawn_frames: 0,
+            shader_watcher,
+            kernel_watcher,
         }
     }
     pub fn draw(&mut self, display: &mut Display) {
-        // Create the uniforms for triplanar mapping + perspective projection for the planet
-        let model_uni = shader::project_triplanar(
-            &self.camera,
-            &self.m_transform,
-            &self.planet_texture,
-            &self.polar_texture,
-        );
-
-        // Draw parameters
-        let params = DrawParameters {
-            depth: Depth {
-                test: DepthTest::IfLess,
-                write: true,
-                ..Default::default()
-            },
-            backface_culling: draw_parameters::BackfaceCullingMode::CullCounterClockwise,
-            ..Default::default()
-        };
-
         // Draw frame
         let mut target = display.draw();
-        target.clear_color_and_depth((0.0, 0.0, 0.0, 0.0), 1.0);
-        if self.cfg.render_cube {
-            target
-                .draw(
-                    &self.cube_vbo,
-                    &self.cube_ibo,
-                    &self.program,
-                    &model_uni,
-                    &params,
-                )
-                .unwrap();
-        }
-        target
-            .draw(&self.vbo, &self.ibo, &self.program, &model_uni, &params)
-            .unwrap();
+        self.renderer
+            .draw(&mut target, &self.camera, &self.m_transform);
         target.finish().unwrap();
+
+        // Save every Nth frame as an image, rendered offscreen at a size independent of the window
+        let interval = self.cfg.capture_interval;
+        if interval > 0 && self.drawn_frames % interval == 0 {
+            let path = frame_path(&self.cfg.capture_dir, self.drawn_frames);
+            if let Err(e) = self.renderer.capture(
+                display,
+                &mut self.camera,
+                &self.m_transform,
+                (self.cfg.capture_width, self.cfg.capture_height),
+                &path,
+            ) {
+                error!("cannot capture the frame to {}: {}", path.display(), e);
+            }
+        }
+        self.drawn_frames += 1;
+    }
+    /// Applies settings that can change while running, see `SettingsChange::Runtime`.
+    pub fn apply_settings(&mut self, cfg: Settings, display: &Display) {
+        self.renderer.apply_settings(display, &self.cfg, &cfg);
+        self.geom_gen.set_laser_strength(cfg.laser_strength);
+        self.cfg = cfg;
+    }
+    /// Recompiles the shaders and the kernels that have changed since the last call, in dev mode.
+    /// Keeps running the previous programs on compile errors.
+    pub fn hot_reload(&mut self, display: &Display) {
+        if let Some(ref mut watcher) = self.shader_watcher {
+            if watcher.changed() {
+                match self.renderer.reload_program(display) {
+                    Ok(()) => info!("reloaded the shaders"),
+                    Err(e) => error!("cannot reload the shaders:\n{}", e),
+                }
+            }
+        }
+        if let Some(ref mut watcher) = self.kernel_watcher {
+            if watcher.changed() {
+                match self.geom_gen.reload_kernels() {
+                    Ok(()) => info!("reloaded the kernels"),
+                    Err(e) => error!("cannot reload the kernels:\n{}", e),
+                }
+            }
+        }
     }
     pub fn late_update(&mut self, display: &mut Display) {
+        self.update_camera();
+        self.renderer.update_mesh(&mut self.geom_gen, display);
+    }
+    /// Moves the camera into the current angles
+    pub fn update_camera(&mut self) {
         self.camera.late_update();
-        self.geom_gen
-            .update_vbo(&mut self.vbo, &mut self.ibo, display);
     }
     pub fn process_events(&mut self, actions: &[Action]) -> Option<ProgramCommand> {
         let cmd = process_global_events(&mut self.camera, &actions);
         process_camera_events(&mut self.cam_control, &actions);
         self.cam_control.update_camera(&mut self.camera);
-        self.process_actions(actions);
+        let loaded = self.process_actions(actions);
 
-        cmd
+        cmd.or(loaded)
     }
     pub fn fixed_update(&mut self, dt: f32) {
+        // Shoot the laser along the view direction
+        let (cam_pos, view_dir) = self.view_ray();
+        self.geom_gen.aim_laser(cam_pos, view_dir);
         self.geom_gen.fixed_update(dt);
+
+        let frame = self.geom_gen.frame_count();
+        let interval = self.cfg.export_interval;
+        if interval > 0 && frame % interval == 0 {
+            self.export_surface();
+        }
+        let interval = self.cfg.volume_export_interval;
+        if interval > 0 && frame % interval == 0 {
+            self.export_volume();
+        }
+    }
+    /// The copies of the simulation buffers from the device to the host since the start
+    pub fn readback_stats(&self) -> &ReadbackStats {
+        self.geom_gen.readback_stats()
     }
     pub fn update(&mut self, dt: f32) {
         self.camera.update(dt);
     }
-    fn process_actions(&mut self, actions: &[Action]) {
+    /// Camera position and view direction in the planet's model space. The planet's model
+    /// transform is the identity.
+    fn view_ray(&self) -> (Vector3f, Vector3f) {
+        let cam_pos = *self.camera.position();
+        let view_dir = *self.camera.target() - cam_pos;
+        (cam_pos.to_vec(), view_dir)
+    }
+    /// Returns the settings of a loaded snapshot for the main loop.
+    fn process_actions(&mut self, actions: &[Action]) -> Option<ProgramCommand> {
+        let mut command = None;
         actions.iter().for_each(|action| {
             use self::Action::*;
             match *action {
                 Shoot(set) => self.geom_gen.explode(set),
+                LaunchImpactor => {
+                    let (cam_pos, view_dir) = self.view_ray();
+                    self.geom_gen.launch_impactor(cam_pos, view_dir);
+                }
+                ExportSurface => self.export_surface(),
+                ExportVolume => self.export_volume(),
+                SaveSnapshot => self.save_snapshot(),
+                LoadSnapshot => {
+                    if let Some(cfg) = self.load_snapshot() {
+                        command = Some(ProgramCommand::SettingsLoaded(cfg));
+                    }
+                }
                 _ => {}
             }
         });
+        command
     }
+    /// Writes the current surface into the export directory.
+    fn export_surface(&mut self) {
+        let format = MeshFormat::from_name(&self.cfg.export_format);
+        let path = export::surface_path(&self.cfg.export_dir, self.geom_gen.frame_count(), format);
+        match self.geom_gen.export_surface(&path, format) {
+            Ok(()) => info!("exported the surface to {}", path.display()),
+            Err(e) => error!("cannot export the surface to {}: {}", path.display(), e),
+        }
+    }
+    /// Writes the volumes into the export directory.
+    fn export_volume(&mut self) {
+        let path = export::volume_path(&self.cfg.export_dir, self.geom_gen.frame_count());
+        match self.geom_gen.export_volume(&path) {
+            Ok(()) => info!("exported the volumes to {}", path.display()),
+            Err(e) => error!("cannot export the volumes to {}: {}", path.display(), e),
+        }
+    }
+    fn save_snapshot(&mut self) {
+        let path = &self.cfg.snapshot_file;
+        match self.geom_gen.snapshot(&self.cfg).save(path) {
+            Ok(()) => info!("saved snapshot to {}", path),
+            Err(e) => error!("cannot save snapshot to {}: {}", path, e),
+        }
+    }
+    /// Replaces the simulation with the one in the snapshot file, including its settings, and
+    /// returns the settings.
+    fn load_snapshot(&mut self) -> Option<Settings> {
+        let path = self.cfg.snapshot_file.clone();
+        match Snapshot::load(&path) {
+            Ok(snapshot) => {
+                self.geom_gen = GeometryGen::from_snapshot(&snapshot, self.fixed_dt);
+                // Keep loading from and saving to the same file
+                self.cfg = Settings {
+                    snapshot_file: path.clone(),
+                    ..snapshot.settings
+                };
+                info!("loaded snapshot from {} at step {}", path, snapshot.frame_count);
+                Some(self.cfg.clone())
+            }
+            Err(e) => {
+                error!("cannot load snapshot from {}: {}", path, e);
+                None
+            }
+        }
+    }
+}
+
+/// The camera that the simulation starts with, looking at the planet along the z-axis
+pub fn default_camera(aspect: f32) -> RotationalCamera {
+    RotationalCamera::new(
+        Point3f::new(0.0, 0.0, 0.0),
+        Deg(0f32),
+        Deg(0f32),
+        1.5f32,
+        aspect,
+    )
 }
 
-/// Opens the image file based on the file type and loads it's contents into a glium-compatible SrgbTexture2d.
-fn load_texture(filename: &str, display: &Display) -> SrgbTexture2d {
-    let img = image::open(filename).expect(&format!("cannot open texture file at {}", filename));
-    let data = img.to_rgb()
-        .pixels()
-        .into_iter()
-        .map(|p| (p.data[0], p.data[1], p.data[2]))
-        .collect::<Vec<(u8, u8, u8)>>()
-        .chunks(img.width() as usize)
-        .map(|x| x.to_vec())
-        .collect::<Vec<Vec<(u8, u8, u8)>>>();
-    SrgbTexture2d::new(display, data).expect(&format!(
-        "unable to create texture from file at {}",
-        filename
-    ))
+/// The transform of the planet, the identity
+pub fn model_transform() -> Decomposedf {
+    Decomposedf {
+        scale: 1f32,
+        rot: Quaternionf::zero(),
+        disp: Vector3f::zero(),
+    }
 }
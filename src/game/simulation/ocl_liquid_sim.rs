@@ -1,145 +1,633 @@
 use ocl;
-use ocl::{Buffer, Kernel, OclPrm, ProQue, SpatialDims};
-use ocl::enums::ArgVal;
-
-/// cell_dist = cell distance in simulation space (0..1)
-pub fn bind<T, D>(
-    src: &str,
-    func: &str,
-    mass_bufs: [&[T]; 2],
-    // Flows are float3's, not floats
-    flow_bufs: [&[T]; 2],
-    temp_bufs: [&[T]; 2],
-    dims: D,
-    fixed_deltatime: f32,
-    cell_dist: f32,
-) -> (Kernel, [Buffer<T>; 2], [Buffer<T>; 2], [Buffer<T>; 2])
-where
-    T: OclPrm,
-    D: Into<SpatialDims>,
-{
-    let mut program_builder = ocl::builders::ProgramBuilder::new();
-    program_builder
-        .source_file(src)
-        .cmplr_opt(format!("-D DT={}", fixed_deltatime))
-        .cmplr_opt(format!("-D DX={}", cell_dist))
-        .cmplr_opt(format!("-D DX3={}", cell_dist * cell_dist * cell_dist));
-    let pro_que = ProQue::builder()
-        .prog_bldr(program_builder)
-        .dims(dims)
-        .build()
-        .unwrap();
-
-    let mass_buf_0 = unsafe {
-        pro_que
-            .buffer_builder::<T>()
-            .use_host_slice(mass_bufs[0])
-            .build()
-            .unwrap()
-    };
-    let mass_buf_1 = unsafe {
-        pro_que
-            .buffer_builder::<T>()
-            .use_host_slice(mass_bufs[1])
-            .build()
-            .unwrap()
-    };
+use ocl::{Buffer, Device, DeviceType, Kernel, OclPrm, Platform, ProQue, Program, Queue,
+          SpatialDims};
+use ocl::builders::ProgramBuilder;
+use ocl::enums::{ArgVal, DeviceInfo, DeviceInfoResult};
+use ocl::prm::Float3;
+use std::path::PathBuf;
+use super::backend::*;
+use super::gravity;
+use super::ocl_marching_cubes;
+use super::ocl_marching_cubes::OclMarchingCubes;
 
-    let flow_buf_0 = unsafe {
-        pro_que
-            .buffer_builder::<T>()
-            .len(flow_bufs[0].len())
-            .use_host_slice(flow_bufs[0])
-            .build()
-            .unwrap()
-    };
-    let flow_buf_1 = unsafe {
-        pro_que
-            .buffer_builder::<T>()
-            .len(flow_bufs[1].len())
-            .use_host_slice(flow_bufs[1])
-            .build()
-            .unwrap()
+const SRC: &str = "src/game/simulation/cl/liquid_sim.cl";
+const FLOW_FUNC: &str = "compute_flow";
+const LIMIT_FUNC: &str = "limit_outflow";
+const INFLOW_FUNC: &str = "sum_inflow";
+const INFLOW_LIMIT_FUNC: &str = "limit_inflow";
+const MASS_FUNC: &str = "apply_mass";
+const HEAT_FUNC: &str = "inject_heat";
+const COARSEN_FUNC: &str = "coarsen_mass";
+const GRAVITY_FUNC: &str = "compute_gravity";
+const EJECTED_FUNC: &str = "sum_ejected";
+
+/// The kernel sources that are compiled at runtime
+pub fn kernel_files() -> Vec<PathBuf> {
+    vec![PathBuf::from(SRC), ocl_marching_cubes::kernel_file()]
+}
+
+/// Runs the liquid simulation with the OpenCL kernel at `cl/liquid_sim.cl`.
+pub struct OclLiquidSim {
+    params: SimulationParams,
+    pro_que: ProQue,
+    kernels: Kernels,
+    bufs: DeviceBufs,
+    // None if disabled or if it cannot run on the device
+    marching_cubes: Option<OclMarchingCubes>,
+}
+
+struct Kernels {
+    flow: Kernel,
+    limit: Kernel,
+    inflow: Kernel,
+    inflow_limit: Kernel,
+    mass: Kernel,
+    heat: Kernel,
+    coarsen: Kernel,
+    gravity: Kernel,
+    ejected: Kernel,
+}
+
+struct DeviceBufs {
+    mass: [Buffer<f32>; 2],
+    flow: [Buffer<f32>; 2],
+    temp: [Buffer<f32>; 2],
+    // Blocks of the coarse grid as float4's
+    coarse: Buffer<f32>,
+    // Per-cell gravity acceleration as float3's
+    gravity: Buffer<f32>,
+    // Flows before limiting, as float3's
+    raw_flow: Buffer<f32>,
+    // Per-cell factor that the flows leaving the cell are limited with
+    limit: Buffer<f32>,
+    // Mass that enters each cell on this step, after the outflow limits
+    inflow: Buffer<f32>,
+    // Per-cell factor that the flows entering the cell are limited with
+    inflow_limit: Buffer<f32>,
+    // Mass deleted by open boundaries, accumulated per border cell
+    ejected: Buffer<f32>,
+    // Sum of the ejected mass, the only part of it that is copied back
+    ejected_total: Buffer<f32>,
+}
+
+impl OclLiquidSim {
+    /// Compiles the kernel and creates the device buffers from the host-side double buffers. The
+    /// buffers stay on the device and are only copied back on `download`.
+    pub fn new(params: &SimulationParams, host_bufs: [Fields; 2]) -> ocl::Result<OclLiquidSim> {
+        let (_, coarse_dim) = gravity::blocks(params.dim);
+        // Fail early and without a panic on systems with no OpenCL ICD
+        let platform = select_platform(&params.platform)?;
+        let device = select_device(platform, &params.device)?;
+        info!(
+            "OpenCL device: {} on {}",
+            device.name().unwrap_or_default(),
+            platform.name().unwrap_or_default()
+        );
+        let pro_que = ProQue::builder()
+            .platform(platform)
+            .device(device)
+            .prog_bldr(program_builder(params))
+            .dims((params.dim, params.dim, params.dim))
+            .build()?;
+
+        let mass_buf_0 = copy_host_slice(&pro_que, host_bufs[0].mass)?;
+        let mass_buf_1 = copy_host_slice(&pro_que, host_bufs[1].mass)?;
+        // Flows are float3's, not floats
+        let flow_buf_0 = copy_host_slice(&pro_que, host_bufs[0].flow)?;
+        let flow_buf_1 = copy_host_slice(&pro_que, host_bufs[1].flow)?;
+        // Temperatures
+        let temp_buf_0 = copy_host_slice(&pro_que, host_bufs[0].temp)?;
+        let temp_buf_1 = copy_host_slice(&pro_que, host_bufs[1].temp)?;
+        // Gravity, device-only
+        let coarse_buf = pro_que
+            .buffer_builder::<f32>()
+            .len(coarse_dim * coarse_dim * coarse_dim * 4)
+            .build()?;
+        let gravity_buf = pro_que
+            .buffer_builder::<f32>()
+            .len(host_bufs[0].flow.len())
+            .build()?;
+        // Intermediate results of the liquid passes, device-only
+        let raw_flow_buf = pro_que
+            .buffer_builder::<f32>()
+            .len(host_bufs[0].flow.len())
+            .build()?;
+        let limit_buf = pro_que.buffer_builder::<f32>().build()?;
+        let inflow_buf = pro_que.buffer_builder::<f32>().build()?;
+        let inflow_limit_buf = pro_que.buffer_builder::<f32>().build()?;
+        let ejected_buf = pro_que
+            .buffer_builder::<f32>()
+            .fill_val(0f32)
+            .build()?;
+        let ejected_total_buf = pro_que.buffer_builder::<f32>().len(1).build()?;
+
+        let bufs = DeviceBufs {
+            mass: [mass_buf_0, mass_buf_1],
+            flow: [flow_buf_0, flow_buf_1],
+            temp: [temp_buf_0, temp_buf_1],
+            coarse: coarse_buf,
+            gravity: gravity_buf,
+            raw_flow: raw_flow_buf,
+            limit: limit_buf,
+            inflow: inflow_buf,
+            inflow_limit: inflow_limit_buf,
+            ejected: ejected_buf,
+            ejected_total: ejected_total_buf,
+        };
+        let kernels = build_kernels(
+            pro_que.program(),
+            pro_que.queue(),
+            pro_que.dims().clone(),
+            coarse_dim,
+            &bufs,
+        )?;
+
+        // Meshing on the device is optional, the surface can always be extracted on the host
+        let marching_cubes = if params.marching_cubes {
+            match OclMarchingCubes::new(
+                pro_que.context(),
+                pro_que.device(),
+                pro_que.queue(),
+                params.dim,
+                &bufs.mass[0],
+            ) {
+                Ok(marching_cubes) => Some(marching_cubes),
+                Err(e) => {
+                    warn!("cannot run marching cubes on the device, using the CPU: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        Ok(OclLiquidSim {
+            params: params.clone(),
+            pro_que,
+            kernels,
+            bufs,
+            marching_cubes,
+        })
+    }
+}
+
+/// Selects a platform by index or by a case-insensitive substring of its name. Empty selects
+/// the first platform.
+fn select_platform(spec: &str) -> ocl::Result<Platform> {
+    if spec.is_empty() {
+        return Ok(Platform::first()?);
+    }
+    let platforms = Platform::list();
+    let found = match spec.parse::<usize>() {
+        Ok(i) => platforms.get(i).cloned(),
+        Err(_) => platforms
+            .into_iter()
+            .find(|p| contains_ignore_case(&p.name().unwrap_or_default(), spec)),
     };
+    found.ok_or_else(|| format!("no OpenCL platform matches \"{}\"", spec).into())
+}
 
-    // Temperatures
-    let temp_buf_0 = unsafe {
-        pro_que
-            .buffer_builder::<T>()
-            .use_host_slice(temp_bufs[0])
-            .build()
-            .unwrap()
+/// Selects a device of the platform by index, by type ("cpu", "gpu" or "accelerator") or by
+/// a case-insensitive substring of its name. Empty selects the first device.
+fn select_device(platform: Platform, spec: &str) -> ocl::Result<Device> {
+    let device_type = match spec.to_lowercase().as_str() {
+        "cpu" => Some(DeviceType::CPU),
+        "gpu" => Some(DeviceType::GPU),
+        "accelerator" => Some(DeviceType::ACCELERATOR),
+        _ => None,
     };
-    let temp_buf_1 = unsafe {
-        pro_que
-            .buffer_builder::<T>()
-            .use_host_slice(temp_bufs[1])
-            .build()
-            .unwrap()
+    let devices = Device::list(platform, device_type)?;
+    let found = if spec.is_empty() || device_type.is_some() {
+        devices.first().cloned()
+    } else {
+        match spec.parse::<usize>() {
+            Ok(i) => devices.get(i).cloned(),
+            Err(_) => devices
+                .into_iter()
+                .find(|d| contains_ignore_case(&d.name().unwrap_or_default(), spec)),
+        }
     };
+    found.ok_or_else(|| format!("no OpenCL device matches \"{}\"", spec).into())
+}
 
-    let kernel = pro_que
-        .kernel_builder(func)
-        .arg(&mass_buf_0)
-        .arg(&flow_buf_0)
-        .arg(&temp_buf_0)
-        .arg(&mass_buf_1)
-        .arg(&flow_buf_1)
-        .arg(&temp_buf_1)
-        .build()
-        .unwrap();
-
-    (
-        kernel,
-        [mass_buf_0, mass_buf_1],
-        [flow_buf_0, flow_buf_1],
-        [temp_buf_0, temp_buf_1],
-    )
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
+/// Prints the available OpenCL platforms and their devices with the indices that the
+/// `opencl_platform` and `opencl_device` settings accept.
+pub fn print_devices() -> ocl::Result<()> {
+    let platforms = Platform::list();
+    if platforms.is_empty() {
+        println!("no OpenCL platforms available");
+    }
+    for (i, platform) in platforms.into_iter().enumerate() {
+        println!(
+            "platform {}: {} ({})",
+            i,
+            platform.name()?,
+            platform.vendor()?
+        );
+        for (j, device) in Device::list_all(platform)?.into_iter().enumerate() {
+            let device_type = match device.info(DeviceInfo::Type)? {
+                DeviceInfoResult::Type(t) => format!("{:?}", t),
+                other => format!("{}", other),
+            };
+            println!("  device {}: {} [{}]", j, device.name()?, device_type);
+        }
+    }
+    Ok(())
+}
+
+/// Sets the simulation parameters as compile-time constants of the program.
+fn program_builder(params: &SimulationParams) -> ProgramBuilder<'static> {
+    let cell_dist = params.cell_dist;
+    let (block, coarse_dim) = gravity::blocks(params.dim);
+    let mut program_builder = ProgramBuilder::new();
+    program_builder
+        .source_file(SRC)
+        // Single precision and correctly rounded like on the CPU backend
+        .cmplr_opt("-cl-fp32-correctly-rounded-divide-sqrt")
+        .cmplr_opt(format!("-D DT={:?}f", params.fixed_dt))
+        .cmplr_opt(format!("-D DX={:?}f", cell_dist))
+        .cmplr_opt(format!("-D DX3={:?}f", cell_dist * cell_dist * cell_dist))
+        .cmplr_opt(format!("-D RADIATE={:?}f", params.radiate))
+        .cmplr_opt(format!("-D DIFFUSE={:?}f", params.diffuse))
+        .cmplr_opt(format!("-D MELT_TEMP={:?}f", params.melt_temp))
+        .cmplr_opt(format!("-D DIM={}", params.dim))
+        .cmplr_opt(format!("-D BLOCK={}", block))
+        .cmplr_opt(format!("-D COARSE_DIM={}", coarse_dim))
+        .cmplr_opt(format!(
+            "-D G={:?}f",
+            gravity::cell_constant(params.gravity_constant, params.dim)
+        ))
+        .cmplr_opt(format!("-D BOUNDARY={}", params.boundary.cl_value()));
+    program_builder
+}
+
+/// Creates the kernels of the program and binds the buffers that don't change between calls.
+fn build_kernels(
+    program: &Program,
+    queue: &Queue,
+    dims: SpatialDims,
+    coarse_dim: usize,
+    bufs: &DeviceBufs,
+) -> ocl::Result<Kernels> {
+    // The double buffers are swapped on each call, see `call`
+    let flow = Kernel::builder()
+        .program(program)
+        .name(FLOW_FUNC)
+        .queue(queue.clone())
+        .global_work_size(dims)
+        .arg(&bufs.mass[0])
+        .arg(&bufs.flow[0])
+        .arg(&bufs.temp[0])
+        .arg(&bufs.gravity)
+        .arg(&bufs.raw_flow)
+        .build()?;
+    let limit = Kernel::builder()
+        .program(program)
+        .name(LIMIT_FUNC)
+        .queue(queue.clone())
+        .global_work_size(dims)
+        .arg(&bufs.mass[0])
+        .arg(&bufs.raw_flow)
+        .arg(&bufs.limit)
+        .build()?;
+    let inflow = Kernel::builder()
+        .program(program)
+        .name(INFLOW_FUNC)
+        .queue(queue.clone())
+        .global_work_size(dims)
+        .arg(&bufs.raw_flow)
+        .arg(&bufs.limit)
+        .arg(&bufs.inflow)
+        .build()?;
+    let inflow_limit = Kernel::builder()
+        .program(program)
+        .name(INFLOW_LIMIT_FUNC)
+        .queue(queue.clone())
+        .global_work_size(dims)
+        .arg(&bufs.mass[0])
+        .arg(&bufs.raw_flow)
+        .arg(&bufs.limit)
+        .arg(&bufs.inflow)
+        .arg(&bufs.inflow_limit)
+        .build()?;
+    let mass = Kernel::builder()
+        .program(program)
+        .name(MASS_FUNC)
+        .queue(queue.clone())
+        .global_work_size(dims)
+        .arg(&bufs.mass[0])
+        .arg(&bufs.flow[0])
+        .arg(&bufs.temp[0])
+        .arg(&bufs.raw_flow)
+        .arg(&bufs.limit)
+        .arg(&bufs.inflow_limit)
+        .arg(&bufs.mass[1])
+        .arg(&bufs.flow[1])
+        .arg(&bufs.temp[1])
+        .arg(&bufs.ejected)
+        .build()?;
+
+    // The mass buffer is set on each call
+    let coarsen = Kernel::builder()
+        .program(program)
+        .name(COARSEN_FUNC)
+        .queue(queue.clone())
+        .global_work_size((coarse_dim, coarse_dim, coarse_dim))
+        .arg(&bufs.mass[0])
+        .arg(&bufs.coarse)
+        .build()?;
+    let gravity = Kernel::builder()
+        .program(program)
+        .name(GRAVITY_FUNC)
+        .queue(queue.clone())
+        .global_work_size(dims)
+        .arg(&bufs.coarse)
+        .arg(&bufs.gravity)
+        .build()?;
+    let ejected = Kernel::builder()
+        .program(program)
+        .name(EJECTED_FUNC)
+        .queue(queue.clone())
+        .global_work_size(1)
+        .arg(&bufs.ejected)
+        .arg(&bufs.ejected_total)
+        .build()?;
+
+    // Adds heat into the temperature buffer; the arguments are set on each call
+    let heat = Kernel::builder()
+        .program(program)
+        .name(HEAT_FUNC)
+        .queue(queue.clone())
+        .global_work_size(dims)
+        .arg(&bufs.temp[0])
+        .arg(Float3::new(0f32, 0f32, 0f32))
+        .arg(Float3::new(0f32, 0f32, 0f32))
+        .arg(0f32)
+        .arg(0f32)
+        .arg(0f32)
+        .build()?;
+
+    Ok(Kernels {
+        flow,
+        limit,
+        inflow,
+        inflow_limit,
+        mass,
+        heat,
+        coarsen,
+        gravity,
+        ejected,
+    })
+}
+
+impl SimulationBackend for OclLiquidSim {
+    fn name(&self) -> &'static str {
+        "OpenCL"
+    }
+
+    fn simulate(&mut self, _: Fields, _: FieldsMut, frame_count: usize) {
+        // Gravity pass over the mass that is read on this step
+        unsafe {
+            self.kernels
+                .coarsen
+                .set_arg_unchecked(0, ArgVal::mem(&self.bufs.mass[frame_count % 2]))
+                .unwrap();
+            self.kernels.coarsen.enq().unwrap();
+            self.kernels.gravity.enq().unwrap();
+        }
+
+        // The results stay on the device until they are downloaded
+        call(&self.kernels, &self.bufs, frame_count);
+    }
+
+    fn upload(&mut self, src: Fields, frame_count: usize) {
+        let read = frame_count % 2;
+        self.bufs.mass[read].write(src.mass).enq().unwrap();
+        self.bufs.flow[read].write(src.flow).enq().unwrap();
+        self.bufs.temp[read].write(src.temp).enq().unwrap();
+    }
+
+    fn download(&mut self, dst: FieldsMut, frame_count: usize) -> usize {
+        let read = frame_count % 2;
+        self.bufs.mass[read].read(&mut *dst.mass).enq().unwrap();
+        self.bufs.flow[read].read(&mut *dst.flow).enq().unwrap();
+        self.bufs.temp[read].read(&mut *dst.temp).enq().unwrap();
+        (dst.mass.len() + dst.flow.len() + dst.temp.len()) * 4
+    }
+
+    fn download_mass(&mut self, mass: &mut [f32], frame_count: usize) -> usize {
+        self.bufs.mass[frame_count % 2].read(&mut *mass).enq().unwrap();
+        mass.len() * 4
+    }
+
+    fn ejected_mass(&mut self) -> (f32, usize) {
+        let mut total = vec![0f32; 1];
+        unsafe {
+            self.kernels.ejected.enq().unwrap();
+        }
+        self.bufs.ejected_total.read(&mut total).enq().unwrap();
+        (total[0], 4)
+    }
+
+    fn reload(&mut self) -> Result<(), String> {
+        let (_, coarse_dim) = gravity::blocks(self.params.dim);
+        // The buffers stay in the same context and are bound to the new kernels
+        let program = program_builder(&self.params)
+            .devices(self.pro_que.device())
+            .build(self.pro_que.context())
+            .map_err(|e| e.to_string())?;
+        let kernels = build_kernels(
+            &program,
+            self.pro_que.queue(),
+            self.pro_que.dims().clone(),
+            coarse_dim,
+            &self.bufs,
+        ).map_err(|e| e.to_string())?;
+        let marching_cubes_kernels = match self.marching_cubes {
+            Some(ref marching_cubes) => Some(
+                marching_cubes
+                    .compile(
+                        self.pro_que.context(),
+                        self.pro_que.device(),
+                        &self.bufs.mass[0],
+                    )
+                    .map_err(|e| e.to_string())?,
+            ),
+            None => None,
+        };
+
+        // Both programs compiled, replace the kernels together
+        self.kernels = kernels;
+        if let (Some(marching_cubes), Some(kernels)) =
+            (self.marching_cubes.as_mut(), marching_cubes_kernels)
+        {
+            marching_cubes.set_kernels(kernels);
+        }
+        Ok(())
+    }
+
+    fn extract_surface(&mut self, vertices: &mut Vec<f32>, frame_count: usize) -> bool {
+        let result = match self.marching_cubes {
+            Some(ref mut marching_cubes) => {
+                marching_cubes.extract(&self.bufs.mass[frame_count % 2], vertices)
+            }
+            None => return false,
+        };
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("marching cubes failed on the device, using the CPU: {}", e);
+                self.marching_cubes = None;
+                false
+            }
+        }
+    }
+
+    fn inject_heat(&mut self, _: &mut [f32], beam: &HeatBeam, frame_count: usize) {
+        let temp_buf = &self.bufs.temp[frame_count % 2];
+        let heat_kernel = &self.kernels.heat;
+        let origin = Float3::new(beam.origin.x, beam.origin.y, beam.origin.z);
+        let dir = Float3::new(beam.dir.x, beam.dir.y, beam.dir.z);
+        unsafe {
+            heat_kernel
+                .set_arg_unchecked(0, ArgVal::mem(temp_buf))
+                .unwrap();
+            heat_kernel
+                .set_arg_unchecked(1, ArgVal::vector(&origin))
+                .unwrap();
+            heat_kernel
+                .set_arg_unchecked(2, ArgVal::vector(&dir))
+                .unwrap();
+            heat_kernel
+                .set_arg_unchecked(3, ArgVal::scalar(&beam.radius))
+                .unwrap();
+            heat_kernel
+                .set_arg_unchecked(4, ArgVal::scalar(&beam.depth))
+                .unwrap();
+            heat_kernel
+                .set_arg_unchecked(5, ArgVal::scalar(&beam.power))
+                .unwrap();
+            heat_kernel.enq().unwrap();
+        }
+    }
 }
 
-pub fn call<T>(
-    kernel: &Kernel,
-    mass_bufs: &[Buffer<T>; 2],
-    // Flows are float3's, not floats
-    flow_bufs: &[Buffer<T>; 2],
-    temp_bufs: &[Buffer<T>; 2],
-    frame_count: usize,
-) where
+/// Creates a device buffer with a copy of the given host slice.
+fn copy_host_slice<T>(pro_que: &ProQue, slice: &[T]) -> ocl::Result<Buffer<T>>
+where
     T: OclPrm,
 {
+    pro_que
+        .buffer_builder::<T>()
+        .len(slice.len())
+        .copy_host_slice(slice)
+        .build()
+}
+
+/// Runs the liquid passes. The queue is in-order, so each pass sees the results of the previous
+/// ones.
+fn call(kernels: &Kernels, bufs: &DeviceBufs, frame_count: usize) {
     // Double buffering!!!
     let even = frame_count % 2;
     let odd = (frame_count + 1) % 2;
-    let read_mass_buf = &mass_bufs[even];
-    let write_mass_buf = &mass_bufs[odd];
-    let read_flow_buf = &flow_bufs[even];
-    let write_flow_buf = &flow_bufs[odd];
-    let read_temp_buf = &temp_bufs[even];
-    let write_temp_buf = &temp_bufs[odd];
+    let read = [&bufs.mass[even], &bufs.flow[even], &bufs.temp[even]];
+    let write = [&bufs.mass[odd], &bufs.flow[odd], &bufs.temp[odd]];
     unsafe {
-        kernel
-            .set_arg_unchecked(0, ArgVal::mem(read_mass_buf))
-            .unwrap();
-        kernel
-            .set_arg_unchecked(1, ArgVal::mem(read_flow_buf))
-            .unwrap();
-        kernel
-            .set_arg_unchecked(2, ArgVal::mem(read_temp_buf))
-            .unwrap();
-        kernel
-            .set_arg_unchecked(3, ArgVal::mem(write_mass_buf))
-            .unwrap();
-        kernel
-            .set_arg_unchecked(4, ArgVal::mem(write_flow_buf))
+        for (i, buf) in read.iter().enumerate() {
+            let i = i as u32;
+            kernels.flow.set_arg_unchecked(i, ArgVal::mem(*buf)).unwrap();
+            kernels.mass.set_arg_unchecked(i, ArgVal::mem(*buf)).unwrap();
+        }
+        kernels
+            .limit
+            .set_arg_unchecked(0, ArgVal::mem(read[0]))
             .unwrap();
-        kernel
-            .set_arg_unchecked(5, ArgVal::mem(write_temp_buf))
+        kernels
+            .inflow_limit
+            .set_arg_unchecked(0, ArgVal::mem(read[0]))
             .unwrap();
-        kernel.enq().unwrap();
+        for (i, buf) in write.iter().enumerate() {
+            kernels
+                .mass
+                .set_arg_unchecked(6 + i as u32, ArgVal::mem(*buf))
+                .unwrap();
+        }
+        kernels.flow.enq().unwrap();
+        kernels.limit.enq().unwrap();
+        kernels.inflow.enq().unwrap();
+        kernels.inflow_limit.enq().unwrap();
+        kernels.mass.enq().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::backend::tests::*;
+    use super::super::cpu_liquid_sim::CpuLiquidSim;
+
+    const DIM: usize = 16;
+    const STEPS: usize = 20;
+
+    fn assert_close(name: &str, cpu: &[f32], ocl: &[f32]) {
+        assert_eq!(cpu.len(), ocl.len());
+        for (i, (a, b)) in cpu.iter().zip(ocl).enumerate() {
+            assert!(
+                (a - b).abs() <= 1e-5f32 * (1f32 + a.abs().max(b.abs())),
+                "{} differs at {}: {} on the CPU, {} on the device",
+                name,
+                i,
+                a,
+                b
+            );
+        }
+    }
+
+    /// Whether the system has an OpenCL device to run the kernels on. Any other error of the
+    /// backend, eg. a kernel that doesn't compile, fails the tests.
+    fn has_device() -> bool {
+        match Platform::first() {
+            Ok(platform) => Device::list(platform, None)
+                .map(|devices| !devices.is_empty())
+                .unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+
+    #[test]
+    fn kernels_match_cpu_backend() {
+        if !has_device() {
+            println!("no OpenCL device, skipped");
+            return;
+        }
+        for &boundary in &[Boundary::Open, Boundary::Closed, Boundary::Periodic] {
+            let params = params(DIM, boundary);
+            let mut cpu_bufs = [HostFields::molten_ball(DIM), HostFields::molten_ball(DIM)];
+            let mut ocl_bufs = cpu_bufs.clone();
+
+            let mut ocl =
+                OclLiquidSim::new(&params, [ocl_bufs[0].view(), ocl_bufs[1].view()]).unwrap();
+            let mut cpu = CpuLiquidSim::new(&params);
+            run(&mut cpu, &mut cpu_bufs, STEPS);
+            run(&mut ocl, &mut ocl_bufs, STEPS);
 
-        // Mapping causes the buffers to flush to the host pointer
-        write_mass_buf.map().read().enq().unwrap();
-        write_flow_buf.map().read().enq().unwrap();
-        write_temp_buf.map().read().enq().unwrap();
+            let (cpu_fields, ocl_fields) = (&cpu_bufs[STEPS % 2], &ocl_bufs[STEPS % 2]);
+            assert_close("mass", &cpu_fields.mass, &ocl_fields.mass);
+            // The padding of the float3's is undefined on the device
+            let components = |flow: &[f32]| -> Vec<f32> {
+                flow.chunks(FLOW_STRIDE).flat_map(|f| f[..3].to_vec()).collect()
+            };
+            assert_close(
+                "flow",
+                &components(&cpu_fields.flow),
+                &components(&ocl_fields.flow),
+            );
+            assert_close("temp", &cpu_fields.temp, &ocl_fields.temp);
+            assert_close("ejected", &[cpu.ejected_mass().0], &[ocl.ejected_mass().0]);
+        }
     }
 }
@@ -0,0 +1,480 @@
+use ocl;
+use ocl::{Buffer, Context, Device, Kernel, Program, Queue};
+use ocl::enums::ArgVal;
+use std::path::PathBuf;
+
+const SRC: &str = "src/game/simulation/cl/marching_cubes.cl";
+const CLASSIFY_FUNC: &str = "classify_cubes";
+const SCAN_GROUPS_FUNC: &str = "scan_groups";
+const SCAN_SUMS_FUNC: &str = "scan_group_sums";
+const ADD_OFFSETS_FUNC: &str = "add_group_offsets";
+const GENERATE_FUNC: &str = "generate_triangles";
+
+/// Work-group size of the prefix sum
+const SCAN_GROUP: usize = 256;
+/// Entries per case in the triangle table: up to five triangles and a terminating -1
+const TABLE_ROW: usize = 16;
+/// Interleaved position and normal
+const VERTEX_FLOATS: usize = 6;
+/// Room for the vertices before the first extraction, grown on demand
+const INITIAL_VERTEX_FLOATS: usize = 1 << 16;
+
+/// Corners of each edge of a cube. The bits of a corner index are its x, y and z offsets.
+const EDGES: [(usize, usize); 12] = [
+    (0, 1),
+    (0, 2),
+    (0, 4),
+    (1, 3),
+    (1, 5),
+    (2, 3),
+    (2, 6),
+    (3, 7),
+    (4, 5),
+    (4, 6),
+    (5, 7),
+    (6, 7),
+];
+
+/// Corners of each face of a cube, counter-clockwise seen from outside of the cube
+const FACES: [[usize; 4]; 6] = [
+    [4, 6, 2, 0],
+    [1, 3, 7, 5],
+    [0, 1, 5, 4],
+    [6, 7, 3, 2],
+    [2, 3, 1, 0],
+    [4, 5, 7, 6],
+];
+
+/// The kernel source that is compiled at runtime
+pub fn kernel_file() -> PathBuf {
+    PathBuf::from(SRC)
+}
+
+/// Extracts the surface of the mass field on the OpenCL device, so that only the triangles are
+/// copied to the host. Runs in three passes: count the triangles of each cube, sum the counts
+/// into offsets and generate the triangles at the offsets.
+pub struct OclMarchingCubes {
+    dim: usize,
+    queue: Queue,
+    kernels: Kernels,
+    bufs: DeviceBufs,
+}
+
+/// The compiled kernels, see `OclMarchingCubes::compile`
+pub struct Kernels {
+    classify: Kernel,
+    scan_groups: Kernel,
+    scan_sums: Kernel,
+    add_offsets: Kernel,
+    generate: Kernel,
+}
+
+struct DeviceBufs {
+    table: Buffer<i8>,
+    // Triangles per cube, padded to whole scan groups
+    counts: Buffer<u32>,
+    // Index of the first triangle of each cube
+    offsets: Buffer<u32>,
+    // Sum of each scan group, followed by the total
+    group_sums: Buffer<u32>,
+    vertices: Buffer<f32>,
+}
+
+impl OclMarchingCubes {
+    /// Compiles the kernels for a `dim`^3 field on the device of the context. `mass` is the
+    /// buffer that is meshed until `extract` is given another one.
+    pub fn new(
+        context: &Context,
+        device: Device,
+        queue: &Queue,
+        dim: usize,
+        mass: &Buffer<f32>,
+    ) -> ocl::Result<OclMarchingCubes> {
+        let cubes = (dim - 1) * (dim - 1) * (dim - 1);
+        let groups = (cubes + SCAN_GROUP - 1) / SCAN_GROUP;
+        let table = triangle_table();
+
+        // The padding is never written and stays zero
+        let bufs = DeviceBufs {
+            table: Buffer::builder()
+                .queue(queue.clone())
+                .len(table.len())
+                .copy_host_slice(&table)
+                .build()?,
+            counts: Buffer::builder()
+                .queue(queue.clone())
+                .len(groups * SCAN_GROUP)
+                .fill_val(0u32)
+                .build()?,
+            offsets: Buffer::builder()
+                .queue(queue.clone())
+                .len(groups * SCAN_GROUP)
+                .build()?,
+            group_sums: Buffer::builder()
+                .queue(queue.clone())
+                .len(groups + 1)
+                .build()?,
+            vertices: Buffer::builder()
+                .queue(queue.clone())
+                .len(INITIAL_VERTEX_FLOATS)
+                .build()?,
+        };
+        let program = build_program(context, device, dim)?;
+        let kernels = build_kernels(&program, queue, dim, &bufs, mass)?;
+
+        Ok(OclMarchingCubes {
+            dim,
+            queue: queue.clone(),
+            kernels,
+            bufs,
+        })
+    }
+
+    /// Recompiles the kernels from the source file. They are only taken into use with
+    /// `set_kernels`, so that the caller can keep the previous kernels if another program fails
+    /// to compile.
+    pub fn compile(
+        &self,
+        context: &Context,
+        device: Device,
+        mass: &Buffer<f32>,
+    ) -> ocl::Result<Kernels> {
+        let program = build_program(context, device, self.dim)?;
+        build_kernels(&program, &self.queue, self.dim, &self.bufs, mass)
+    }
+
+    pub fn set_kernels(&mut self, kernels: Kernels) {
+        self.kernels = kernels;
+    }
+
+    /// Replaces `vertices` with the triangles of the surface in `mass`, three vertices per
+    /// triangle with interleaved positions and normals in the [0, 1] sample space. The triangles
+    /// are clockwise seen from outside of the surface.
+    pub fn extract(&mut self, mass: &Buffer<f32>, vertices: &mut Vec<f32>) -> ocl::Result<()> {
+        let groups = self.bufs.group_sums.len() - 1;
+        unsafe {
+            self.kernels
+                .classify
+                .set_arg_unchecked(0, ArgVal::mem(mass))?;
+            self.kernels
+                .generate
+                .set_arg_unchecked(0, ArgVal::mem(mass))?;
+            self.kernels.classify.enq()?;
+            self.kernels.scan_groups.enq()?;
+            self.kernels.scan_sums.enq()?;
+            self.kernels.add_offsets.enq()?;
+        }
+
+        // The total is the only value that the host needs before generating
+        let mut total = [0u32];
+        self.bufs
+            .group_sums
+            .read(&mut total[..])
+            .offset(groups)
+            .enq()?;
+        let len = total[0] as usize * 3 * VERTEX_FLOATS;
+
+        if len > self.bufs.vertices.len() {
+            self.bufs.vertices = Buffer::builder()
+                .queue(self.queue.clone())
+                .len(len.next_power_of_two())
+                .build()?;
+            unsafe {
+                self.kernels
+                    .generate
+                    .set_arg_unchecked(3, ArgVal::mem(&self.bufs.vertices))?;
+            }
+        }
+
+        vertices.clear();
+        vertices.resize(len, 0f32);
+        if len > 0 {
+            unsafe {
+                self.kernels.generate.enq()?;
+            }
+            self.bufs.vertices.read(&mut vertices[..]).enq()?;
+        }
+        Ok(())
+    }
+}
+
+/// Sets the grid size and the scan group size as compile-time constants of the program.
+fn build_program(context: &Context, device: Device, dim: usize) -> ocl::Result<Program> {
+    Program::builder()
+        .source_file(SRC)
+        .devices(device)
+        .cmplr_opt(format!("-D DIM={}", dim))
+        .cmplr_opt(format!("-D SCAN_GROUP={}", SCAN_GROUP))
+        .cmplr_opt(format!("-D TABLE_ROW={}", TABLE_ROW))
+        .build(context)
+}
+
+/// Creates the kernels of the program and binds the buffers. The mass buffer is set on each
+/// extraction.
+fn build_kernels(
+    program: &Program,
+    queue: &Queue,
+    dim: usize,
+    bufs: &DeviceBufs,
+    mass: &Buffer<f32>,
+) -> ocl::Result<Kernels> {
+    let cube_dims = (dim - 1, dim - 1, dim - 1);
+    let padded = bufs.counts.len();
+    let groups = bufs.group_sums.len() - 1;
+
+    let classify = Kernel::builder()
+        .program(program)
+        .name(CLASSIFY_FUNC)
+        .queue(queue.clone())
+        .global_work_size(cube_dims)
+        .arg(mass)
+        .arg(&bufs.table)
+        .arg(&bufs.counts)
+        .build()?;
+    let scan_groups = Kernel::builder()
+        .program(program)
+        .name(SCAN_GROUPS_FUNC)
+        .queue(queue.clone())
+        .global_work_size(padded)
+        .local_work_size(SCAN_GROUP)
+        .arg(&bufs.counts)
+        .arg(&bufs.offsets)
+        .arg(&bufs.group_sums)
+        .build()?;
+    // A single work item, there are few groups
+    let scan_sums = Kernel::builder()
+        .program(program)
+        .name(SCAN_SUMS_FUNC)
+        .queue(queue.clone())
+        .global_work_size(1)
+        .arg(&bufs.group_sums)
+        .arg(groups as u32)
+        .build()?;
+    let add_offsets = Kernel::builder()
+        .program(program)
+        .name(ADD_OFFSETS_FUNC)
+        .queue(queue.clone())
+        .global_work_size(padded)
+        .arg(&bufs.offsets)
+        .arg(&bufs.group_sums)
+        .build()?;
+    let generate = Kernel::builder()
+        .program(program)
+        .name(GENERATE_FUNC)
+        .queue(queue.clone())
+        .global_work_size(cube_dims)
+        .arg(mass)
+        .arg(&bufs.table)
+        .arg(&bufs.offsets)
+        .arg(&bufs.vertices)
+        .build()?;
+
+    Ok(Kernels {
+        classify,
+        scan_groups,
+        scan_sums,
+        add_offsets,
+        generate,
+    })
+}
+
+/// Builds the triangle table of marching cubes. Bit i of a case is set when corner i of the cube
+/// is inside the surface. Each case lists up to five triangles as edge indices, terminated by
+/// -1. Faces with two diagonal inside corners keep the inside corners apart, which only depends
+/// on the face, so that the neighboring cube agrees and the surface has no holes.
+pub fn triangle_table() -> Vec<i8> {
+    let mut table = vec![-1i8; 256 * TABLE_ROW];
+    for (case, row) in table.chunks_mut(TABLE_ROW).enumerate() {
+        let inside = |corner: usize| case & (1 << corner) != 0;
+
+        // Each run of inside corners on a face crosses the surface on the edges where it starts
+        // and ends. Link the end to the start, which orients the loops clockwise seen from
+        // outside of the surface.
+        let mut next = [None; 12];
+        for face in &FACES {
+            for k in 0..4 {
+                let prev = face[(k + 3) % 4];
+                if !inside(face[k]) || inside(prev) {
+                    continue;
+                }
+                let mut last = k;
+                while inside(face[(last + 1) % 4]) {
+                    last += 1;
+                }
+                let start = edge_index(prev, face[k]);
+                let end = edge_index(face[last % 4], face[(last + 1) % 4]);
+                next[end] = Some(start);
+            }
+        }
+
+        // Follow the links around each loop and triangulate it
+        let mut visited = [false; 12];
+        let mut n = 0;
+        for first in 0..12 {
+            if visited[first] || next[first].is_none() {
+                continue;
+            }
+            let mut polygon = vec![];
+            let mut edge = first;
+            loop {
+                visited[edge] = true;
+                polygon.push(edge);
+                edge = next[edge].unwrap();
+                if edge == first {
+                    break;
+                }
+            }
+            for tri in triangulate(&polygon) {
+                for &edge in &tri {
+                    row[n] = edge as i8;
+                    n += 1;
+                }
+            }
+        }
+    }
+    table
+}
+
+fn edge_index(a: usize, b: usize) -> usize {
+    let key = (a.min(b), a.max(b));
+    EDGES.iter().position(|&e| e == key).unwrap()
+}
+
+/// Fans the polygon from a corner whose diagonals don't run along a face of the cube; such a
+/// diagonal would not match the triangles of the neighboring cube.
+fn triangulate(polygon: &[usize]) -> Vec<[usize; 3]> {
+    let n = polygon.len();
+    let apex = (0..n)
+        .find(|&s| (2..n - 1).all(|i| !share_face(polygon[s], polygon[(s + i) % n])))
+        .expect("no valid triangulation");
+    (1..n - 1)
+        .map(|i| {
+            [
+                polygon[apex],
+                polygon[(apex + i) % n],
+                polygon[(apex + i + 1) % n],
+            ]
+        })
+        .collect()
+}
+
+/// Whether the two edges lie on the same face of the cube
+fn share_face(a: usize, b: usize) -> bool {
+    let corners = [EDGES[a].0, EDGES[a].1, EDGES[b].0, EDGES[b].1];
+    (0..3).any(|axis| {
+        let side = (corners[0] >> axis) & 1;
+        corners.iter().all(|&c| (c >> axis) & 1 == side)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use isosurface::marching_cubes::MarchingCubes;
+    use isosurface::source::{CentralDifference, Source};
+    use ocl::Platform;
+
+    const DIM: usize = 32;
+
+    /// An analytic field in the [0, 1] sample space, negative inside
+    struct Field(fn(f32, f32, f32) -> f32);
+
+    impl Source for Field {
+        fn sample(&self, x: f32, y: f32, z: f32) -> f32 {
+            (self.0)(x, y, z)
+        }
+    }
+
+    fn sphere(x: f32, y: f32, z: f32) -> f32 {
+        let (x, y, z) = (x - 0.5f32, y - 0.5f32, z - 0.5f32);
+        (x * x + y * y + z * z).sqrt() - 0.3f32
+    }
+
+    // Between two layers of samples
+    fn plane(_: f32, _: f32, z: f32) -> f32 {
+        z - 0.45f32
+    }
+
+    /// Samples the field at the grid points like the mass buffer
+    fn grid(field: &Field) -> Vec<f32> {
+        let step = 1f32 / (DIM - 1) as f32;
+        let mut mass = Vec::with_capacity(DIM * DIM * DIM);
+        for z in 0..DIM {
+            for y in 0..DIM {
+                for x in 0..DIM {
+                    mass.push(field.sample(x as f32 * step, y as f32 * step, z as f32 * step));
+                }
+            }
+        }
+        mass
+    }
+
+    /// Counts the triangles of the table for the grid on the host, like `classify_cubes`
+    fn table_triangles(mass: &[f32]) -> usize {
+        let table = triangle_table();
+        let idx = |x: usize, y: usize, z: usize| z * DIM * DIM + y * DIM + x;
+        let mut triangles = 0;
+        for z in 0..DIM - 1 {
+            for y in 0..DIM - 1 {
+                for x in 0..DIM - 1 {
+                    let corner = |i: usize| idx(x + (i & 1), y + ((i >> 1) & 1), z + (i >> 2));
+                    let case = (0..8)
+                        .filter(|&i| mass[corner(i)] < 0f32)
+                        .fold(0, |case, i| case | 1 << i);
+                    let row = &table[case * TABLE_ROW..(case + 1) * TABLE_ROW];
+                    triangles += row.iter().take_while(|&&edge| edge >= 0).count() / 3;
+                }
+            }
+        }
+        triangles
+    }
+
+    /// Counts the triangles of the `isosurface` extractor that the CPU path uses
+    fn cpu_triangles(field: Field) -> usize {
+        let mut vertices = vec![];
+        let mut indices = vec![];
+        MarchingCubes::new(DIM).extract_with_normals(
+            &CentralDifference::new(field),
+            &mut vertices,
+            &mut indices,
+        );
+        indices.len() / 3
+    }
+
+    /// Counts the triangles extracted on the first OpenCL device, None without OpenCL
+    fn device_triangles(mass: &[f32]) -> Option<usize> {
+        let platform = Platform::first().ok()?;
+        let device = Device::first(platform).ok()?;
+        let context = Context::builder()
+            .platform(platform)
+            .devices(device)
+            .build()
+            .ok()?;
+        let queue = Queue::new(&context, device, None).unwrap();
+        let buf = Buffer::builder()
+            .queue(queue.clone())
+            .len(mass.len())
+            .copy_host_slice(mass)
+            .build()
+            .unwrap();
+        let mut marching_cubes =
+            OclMarchingCubes::new(&context, device, &queue, DIM, &buf).unwrap();
+        let mut vertices = vec![];
+        marching_cubes.extract(&buf, &mut vertices).unwrap();
+        Some(vertices.len() / (3 * VERTEX_FLOATS))
+    }
+
+    #[test]
+    fn triangle_counts_match_cpu_extractor() {
+        for &(name, f) in &[("sphere", sphere as fn(f32, f32, f32) -> f32), ("plane", plane)] {
+            let mass = grid(&Field(f));
+            let table = table_triangles(&mass);
+            assert!(table > 0, "{}: no surface", name);
+            assert_eq!(table, cpu_triangles(Field(f)), "{}: CPU extractor", name);
+            match device_triangles(&mass) {
+                Some(device) => assert_eq!(table, device, "{}: device", name),
+                None => println!("no OpenCL device, skipped the device for the {}", name),
+            }
+        }
+    }
+}
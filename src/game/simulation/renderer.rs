@@ -0,0 +1,216 @@
+use glium::*;
+use glium::backend::Facade;
+use glium::framebuffer::{DepthRenderBuffer, SimpleFrameBuffer};
+use glium::texture::{DepthFormat, MipmapsOption, RawImage2d, SrgbTexture2d, Texture2d,
+                     UncompressedFloatFormat};
+use index::PrimitiveType;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str;
+use util::camera::*;
+use util::*;
+use prelude::*;
+use shader;
+use super::geometry::GeometryGen;
+use super::unit_cube::*;
+use super::super::settings::*;
+use image;
+use image::GenericImage;
+
+/// Where the shaders are read from when they are reloaded in dev mode
+const SHADER_DIR: &str = "src/shader";
+const VERTEX_SHADER: &str = "project.140.vert";
+const FRAGMENT_SHADER: &str = "triplanar.140.frag";
+
+/// Draws the planet surface. Works with any GL context, eg. the window or a headless context.
+pub struct Renderer {
+    program: Program,
+    vbo: VertexBuffer<VertexPN>,
+    ibo: IndexBuffer<u32>,
+    cube_vbo: VertexBuffer<VertexPN>,
+    cube_ibo: IndexBuffer<u32>,
+    render_cube: bool,
+    planet_texture: SrgbTexture2d,
+    polar_texture: SrgbTexture2d,
+}
+
+impl Renderer {
+    pub fn new<F: Facade>(facade: &F, cfg: &Settings) -> Renderer {
+        let program = program!(
+            facade,
+            140 => {
+                vertex: str::from_utf8(include_bytes!("../../shader/project.140.vert")).unwrap(),
+                fragment: str::from_utf8(include_bytes!("../../shader/triplanar.140.frag")).unwrap(),
+            }).unwrap();
+
+        // Load the textures for the triplanar mapping
+        let planet_texture = load_texture(&cfg.planet_texture, facade);
+        let polar_texture = load_texture(&cfg.polar_texture, facade);
+
+        Renderer {
+            program,
+            vbo: VertexBuffer::dynamic(facade, &[]).unwrap(),
+            ibo: IndexBuffer::dynamic(facade, PrimitiveType::TrianglesList, &[]).unwrap(),
+            cube_vbo: VertexBuffer::new(facade, &UNIT_CUBE_VBO).unwrap(),
+            cube_ibo: IndexBuffer::new(facade, PrimitiveType::TrianglesList, &UNIT_CUBE_IBO)
+                .unwrap(),
+            render_cube: cfg.render_cube,
+            planet_texture,
+            polar_texture,
+        }
+    }
+
+    /// The shader sources that `reload_program` reads
+    pub fn shader_files() -> Vec<PathBuf> {
+        vec![
+            Path::new(SHADER_DIR).join(VERTEX_SHADER),
+            Path::new(SHADER_DIR).join(FRAGMENT_SHADER),
+        ]
+    }
+
+    /// Recompiles the shaders from the source files. On an error the previous program is kept
+    /// and the error contains the compiler log.
+    pub fn reload_program<F: Facade>(&mut self, facade: &F) -> Result<(), String> {
+        let read = |name: &str| {
+            let path = Path::new(SHADER_DIR).join(name);
+            fs::read_to_string(&path).map_err(|e| format!("cannot read {}: {}", path.display(), e))
+        };
+        let vertex = read(VERTEX_SHADER)?;
+        let fragment = read(FRAGMENT_SHADER)?;
+        self.program = Program::from_source(facade, &vertex, &fragment, None)
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Applies the settings that affect the rendering, reloads the textures if they changed.
+    pub fn apply_settings<F: Facade>(&mut self, facade: &F, old: &Settings, new: &Settings) {
+        self.render_cube = new.render_cube;
+        // Keep the old texture if the new one is broken
+        if new.planet_texture != old.planet_texture {
+            match try_load_texture(&new.planet_texture, facade) {
+                Ok(texture) => self.planet_texture = texture,
+                Err(e) => warn!("{}", e),
+            }
+        }
+        if new.polar_texture != old.polar_texture {
+            match try_load_texture(&new.polar_texture, facade) {
+                Ok(texture) => self.polar_texture = texture,
+                Err(e) => warn!("{}", e),
+            }
+        }
+    }
+
+    /// Extracts the current surface into the vertex buffers.
+    pub fn update_mesh<F: Facade>(&mut self, geom_gen: &mut GeometryGen, facade: &F) {
+        geom_gen.update_vbo(&mut self.vbo, &mut self.ibo, facade);
+    }
+
+    pub fn draw<S: Surface>(&self, target: &mut S, camera: &Camera, m_transform: &Decomposedf) {
+        // Create the uniforms for triplanar mapping + perspective projection for the planet
+        let model_uni = shader::project_triplanar(
+            camera,
+            m_transform,
+            &self.planet_texture,
+            &self.polar_texture,
+        );
+
+        // Draw parameters
+        let params = DrawParameters {
+            depth: Depth {
+                test: DepthTest::IfLess,
+                write: true,
+                ..Default::default()
+            },
+            backface_culling: draw_parameters::BackfaceCullingMode::CullCounterClockwise,
+            ..Default::default()
+        };
+
+        target.clear_color_and_depth((0.0, 0.0, 0.0, 0.0), 1.0);
+        if self.render_cube {
+            target
+                .draw(
+                    &self.cube_vbo,
+                    &self.cube_ibo,
+                    &self.program,
+                    &model_uni,
+                    &params,
+                )
+                .unwrap();
+        }
+        target
+            .draw(&self.vbo, &self.ibo, &self.program, &model_uni, &params)
+            .unwrap();
+    }
+
+    /// Draws into an offscreen framebuffer of the given size and saves the image as a PNG at
+    /// `path`. The camera keeps its aspect ratio.
+    pub fn capture<F: Facade, P: AsRef<Path>>(
+        &self,
+        facade: &F,
+        camera: &mut Camera,
+        m_transform: &Decomposedf,
+        (width, height): (u32, u32),
+        path: P,
+    ) -> Result<(), String> {
+        let color = Texture2d::empty_with_format(
+            facade,
+            UncompressedFloatFormat::U8U8U8U8,
+            MipmapsOption::NoMipmap,
+            width,
+            height,
+        ).map_err(|e| format!("cannot create the color buffer: {:?}", e))?;
+        let depth = DepthRenderBuffer::new(facade, DepthFormat::I24, width, height)
+            .map_err(|e| format!("cannot create the depth buffer: {:?}", e))?;
+        let mut target = SimpleFrameBuffer::with_depth_buffer(facade, &color, &depth)
+            .map_err(|e| format!("cannot create the framebuffer: {:?}", e))?;
+
+        let window_aspect = camera.aspect();
+        camera.set_aspect(width as f32 / height as f32);
+        self.draw(&mut target, camera, m_transform);
+        camera.set_aspect(window_aspect);
+
+        save_frame(color.read(), path)
+    }
+}
+
+/// Saves the pixels read from a framebuffer as a PNG at `path`.
+fn save_frame<P: AsRef<Path>>(raw: RawImage2d<u8>, path: P) -> Result<(), String> {
+    let img = image::ImageBuffer::<image::Rgba<u8>, _>::from_raw(
+        raw.width,
+        raw.height,
+        raw.data.into_owned(),
+    ).ok_or_else(|| "invalid framebuffer contents".to_owned())?;
+    // OpenGL stores the rows from the bottom up
+    let img = image::imageops::flip_vertical(&img);
+
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    img.save(path).map_err(|e| e.to_string())
+}
+
+/// Path of the image captured on the given frame, eg. "frame_000120.png".
+pub fn frame_path<P: AsRef<Path>>(dir: P, frame: usize) -> ::std::path::PathBuf {
+    dir.as_ref().join(format!("frame_{:06}.png", frame))
+}
+
+/// Opens the image file based on the file type and loads it's contents into a glium-compatible SrgbTexture2d.
+fn load_texture<F: Facade>(filename: &str, facade: &F) -> SrgbTexture2d {
+    try_load_texture(filename, facade).unwrap_or_else(|e| panic!("{}", e))
+}
+
+fn try_load_texture<F: Facade>(filename: &str, facade: &F) -> Result<SrgbTexture2d, String> {
+    let img = image::open(filename)
+        .map_err(|e| format!("cannot open texture file at {}: {}", filename, e))?;
+    let data = img.to_rgb()
+        .pixels()
+        .into_iter()
+        .map(|p| (p.data[0], p.data[1], p.data[2]))
+        .collect::<Vec<(u8, u8, u8)>>()
+        .chunks(img.width() as usize)
+        .map(|x| x.to_vec())
+        .collect::<Vec<Vec<(u8, u8, u8)>>>();
+    SrgbTexture2d::new(facade, data)
+        .map_err(|e| format!("unable to create texture from file at {}: {:?}", filename, e))
+}
@@ -0,0 +1,227 @@
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use serde_yaml;
+use game::settings::Settings;
+use super::backend::FLOW_STRIDE;
+
+const MAGIC: &[u8; 8] = b"DSSNAPSH";
+/// Bumped whenever the layout changes, old snapshots are rejected rather than misread
+pub const SNAPSHOT_VERSION: u32 = 1;
+/// Largest grid that a snapshot is read for, the fields of a larger one would take gigabytes
+const MAX_DIM: usize = 256;
+/// Largest settings that a snapshot is read for
+const MAX_SETTINGS_LEN: usize = 1 << 20;
+
+/// The complete state of the liquid simulation at the start of a step. All integers and floats
+/// are stored in little endian, in the order:
+///
+/// - magic `DSSNAPSH`, version (u32)
+/// - dim (u64), frame_count (u64), seed (u64)
+/// - length of the settings (u64) and the settings as YAML
+/// - mass (dim^3 f32), flow (4 * dim^3 f32, float3's padded to four), temperature (dim^3 f32)
+///
+/// The dim must be within [3, 256] and the settings at most 1 MiB.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub dim: usize,
+    pub frame_count: usize,
+    pub seed: u64,
+    /// The settings that the simulation was built with
+    pub settings: Settings,
+    pub mass: Vec<f32>,
+    pub flow: Vec<f32>,
+    pub temp: Vec<f32>,
+}
+
+impl Snapshot {
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.check_lengths()?;
+        w.write_all(MAGIC)?;
+        write_u32(w, SNAPSHOT_VERSION)?;
+        write_u64(w, self.dim as u64)?;
+        write_u64(w, self.frame_count as u64)?;
+        write_u64(w, self.seed)?;
+        let settings = serde_yaml::to_string(&self.settings)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_u64(w, settings.len() as u64)?;
+        w.write_all(settings.as_bytes())?;
+        for field in &[&self.mass, &self.flow, &self.temp] {
+            for &x in field.iter() {
+                write_u32(w, x.to_bits())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(r: &mut R) -> io::Result<Snapshot> {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a simulation snapshot".to_owned()));
+        }
+        let version = read_u32(r)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid(format!(
+                "unsupported snapshot version {}, expected {}",
+                version, SNAPSHOT_VERSION
+            )));
+        }
+        // The sizes are checked before anything is allocated for them
+        let dim = read_u64(r)?;
+        if dim < 3 || dim > MAX_DIM as u64 {
+            return Err(invalid(format!("snapshot dim {} is outside of [3, {}]", dim, MAX_DIM)));
+        }
+        let dim = dim as usize;
+        let frame_count = read_u64(r)? as usize;
+        let seed = read_u64(r)?;
+
+        let len = read_u64(r)?;
+        if len > MAX_SETTINGS_LEN as u64 {
+            return Err(invalid(format!("snapshot settings are too long, {} bytes", len)));
+        }
+        let len = len as usize;
+        let mut settings = vec![0u8; len];
+        r.read_exact(&mut settings)?;
+        let settings: Settings = serde_yaml::from_slice(&settings)
+            .map_err(|e| invalid(format!("invalid settings in snapshot: {}", e)))?;
+        if settings.scalar_field_dim != dim {
+            return Err(invalid(format!(
+                "snapshot dim {} differs from its settings' {}",
+                dim, settings.scalar_field_dim
+            )));
+        }
+
+        let cells = dim * dim * dim;
+        let mass = read_f32s(r, cells)?;
+        let flow = read_f32s(r, FLOW_STRIDE * cells)?;
+        let temp = read_f32s(r, cells)?;
+
+        let snapshot = Snapshot {
+            dim,
+            frame_count,
+            seed,
+            settings,
+            mass,
+            flow,
+            temp,
+        };
+        snapshot.check_lengths()?;
+        Ok(snapshot)
+    }
+
+    /// Checks that the fields hold `dim`^3 cells, as the simulation is built on them as they are.
+    fn check_lengths(&self) -> io::Result<()> {
+        let cells = self.dim * self.dim * self.dim;
+        let fields = [
+            ("mass", self.mass.len(), cells),
+            ("flow", self.flow.len(), FLOW_STRIDE * cells),
+            ("temperature", self.temp.len(), cells),
+        ];
+        for &(name, len, expected) in &fields {
+            if len != expected {
+                return Err(invalid(format!(
+                    "snapshot {} has {} floats, expected {} for dim {}",
+                    name, len, expected, self.dim
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the snapshot into a file at `path`, creating the parent directories if necessary.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut f = io::BufWriter::new(fs::File::create(path)?);
+        self.write(&mut f)?;
+        f.flush()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Snapshot> {
+        let mut f = io::BufReader::new(fs::File::open(path)?);
+        Snapshot::read(&mut f)
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_u32<W: Write>(w: &mut W, x: u32) -> io::Result<()> {
+    w.write_all(&[x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8])
+}
+
+fn write_u64<W: Write>(w: &mut W, x: u64) -> io::Result<()> {
+    write_u32(w, x as u32)?;
+    write_u32(w, (x >> 32) as u32)
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let lo = read_u32(r)? as u64;
+    let hi = read_u32(r)? as u64;
+    Ok(lo | hi << 32)
+}
+
+fn read_f32s<R: Read>(r: &mut R, n: usize) -> io::Result<Vec<f32>> {
+    let mut v = Vec::with_capacity(n);
+    for _ in 0..n {
+        v.push(f32::from_bits(read_u32(r)?));
+    }
+    Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(dim: usize) -> Snapshot {
+        let cells = dim * dim * dim;
+        let mut settings = Settings::default();
+        settings.scalar_field_dim = dim;
+        Snapshot {
+            dim,
+            frame_count: 3,
+            seed: 7,
+            settings,
+            mass: (0..cells).map(|i| i as f32).collect(),
+            flow: vec![0.5f32; FLOW_STRIDE * cells],
+            temp: vec![1f32; cells],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut bytes = vec![];
+        snapshot(4).write(&mut bytes).unwrap();
+        let read = Snapshot::read(&mut &bytes[..]).unwrap();
+        assert_eq!(read.frame_count, 3);
+        assert_eq!(read.mass, snapshot(4).mass);
+        assert_eq!(read.flow, snapshot(4).flow);
+    }
+
+    #[test]
+    fn huge_dim_is_rejected() {
+        let mut bytes = MAGIC.to_vec();
+        write_u32(&mut bytes, SNAPSHOT_VERSION).unwrap();
+        write_u64(&mut bytes, 1 << 40).unwrap();
+        let err = Snapshot::read(&mut &bytes[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn mismatched_lengths_are_not_written() {
+        let mut short = snapshot(4);
+        short.flow.pop();
+        assert!(short.write(&mut vec![]).is_err());
+    }
+}
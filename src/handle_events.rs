@@ -1,7 +1,8 @@
 use glutin;
+use game::Settings;
 use util::camera::*;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Action {
     Exit,
     Refresh,
@@ -16,6 +17,16 @@ pub enum Action {
     CamRotateS(bool),
     /// Shoot laser
     Shoot(bool),
+    /// Launch an impactor
+    LaunchImpactor,
+    /// Write the current surface into a mesh file
+    ExportSurface,
+    /// Write the mass, flow and temperature volumes into a VTK file
+    ExportVolume,
+    /// Save the simulation state into the snapshot file
+    SaveSnapshot,
+    /// Restore the simulation state from the snapshot file
+    LoadSnapshot,
 }
 
 pub fn poll_events(events_loop: &mut glutin::EventsLoop) -> Vec<Action> {
@@ -44,6 +55,35 @@ pub fn poll_events(events_loop: &mut glutin::EventsLoop) -> Vec<Action> {
                                 actions.push(Refresh);
                             }
                         }
+                        // Ctrl + E -> export the surface
+                        VK::E => {
+                            if set && input.modifiers.ctrl {
+                                actions.push(ExportSurface);
+                            }
+                        }
+                        // Ctrl + V -> export the volumes
+                        VK::V => {
+                            if set && input.modifiers.ctrl {
+                                actions.push(ExportVolume);
+                            }
+                        }
+                        // Ctrl + S -> save a snapshot
+                        VK::S => {
+                            if set && input.modifiers.ctrl {
+                                actions.push(SaveSnapshot);
+                            }
+                        }
+                        // Ctrl + L -> load the snapshot
+                        VK::L => {
+                            if set && input.modifiers.ctrl {
+                                actions.push(LoadSnapshot);
+                            }
+                        }
+                        VK::I => {
+                            if set {
+                                actions.push(LaunchImpactor);
+                            }
+                        }
                         VK::Space => {
                             let set = match input.state {
                                 glutin::ElementState::Pressed => true,
@@ -69,6 +109,8 @@ pub fn poll_events(events_loop: &mut glutin::EventsLoop) -> Vec<Action> {
 pub enum ProgramCommand {
     Exit,
     RefreshSimulation,
+    /// The simulation continues with the settings of a loaded snapshot
+    SettingsLoaded(Settings),
 }
 
 pub fn process_global_events(camera: &mut Camera, actions: &[Action]) -> Option<ProgramCommand> {
@@ -16,6 +16,7 @@ extern crate ndarray;
 extern crate obj;
 extern crate ocl;
 extern crate rand;
+extern crate rayon;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -25,16 +26,47 @@ mod game;
 mod init;
 mod handle_events;
 mod prelude;
+mod recording;
 mod shader;
 mod util;
 
+use std::env;
+use std::process;
 use glium::*;
 use game::*;
+use recording::*;
 
 fn main() {
     // Init logging
     env_logger::init();
 
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--list-devices") {
+        if let Err(e) = print_devices() {
+            error!("cannot list the OpenCL devices: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // Run without a window when requested
+    if args.iter().any(|arg| arg == "--headless") {
+        let opts = HeadlessOptions::from_args(&args).unwrap_or_else(|e| {
+            eprintln!("{}\n{}", e, HEADLESS_USAGE);
+            process::exit(2);
+        });
+        if let Err(e) = run_headless(&opts) {
+            error!("headless run failed: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let input_mode = InputMode::from_args(&args).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, INPUT_USAGE);
+        process::exit(2);
+    });
+
     // Init context
     let events_loop = glutin::EventsLoop::new();
     let viewport = Rect {
@@ -46,7 +78,7 @@ fn main() {
     let display = init::open_display("Planetary destruction simulator", viewport, &events_loop);
 
     // Init game state
-    let mut game = GameStruct::new(events_loop, display);
+    let mut game = GameStruct::new(events_loop, display, input_mode);
 
     // Run game
     let mut current = GameFn::new(GameStruct::init);
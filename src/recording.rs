@@ -0,0 +1,186 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde_yaml;
+use handle_events::Action;
+
+/// An action and the fixed step it was applied on
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedAction {
+    pub step: usize,
+    pub action: Action,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Recording {
+    pub actions: Vec<RecordedAction>,
+}
+
+/// Logs the actions of a session. The same recorder is kept when the simulation is rebuilt, and the
+/// log is written into the file when the recorder is dropped, ie. when the session ends.
+pub struct Recorder {
+    path: PathBuf,
+    recording: Recording,
+}
+
+impl Recorder {
+    pub fn new<P: AsRef<Path>>(path: P) -> Recorder {
+        Recorder {
+            path: path.as_ref().to_owned(),
+            recording: Recording::default(),
+        }
+    }
+
+    /// Logs the actions applied on `step`. Refreshing is logged too, so that a replay rebuilds the
+    /// simulation on the same step.
+    pub fn record(&mut self, step: usize, actions: &[Action]) {
+        for action in actions {
+            self.recording.actions.push(RecordedAction {
+                step,
+                action: action.clone(),
+            });
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let f = fs::File::create(&self.path)?;
+        serde_yaml::to_writer(f, &self.recording)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        match self.save() {
+            Ok(()) => info!(
+                "saved {} recorded actions to {}",
+                self.recording.actions.len(),
+                self.path.display()
+            ),
+            Err(e) => error!("cannot save the recording to {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+/// Feeds the actions of a recording back step by step.
+pub struct Player {
+    recording: Recording,
+    next: usize,
+}
+
+impl Player {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Player> {
+        let f = fs::File::open(path)?;
+        let recording: Recording = serde_yaml::from_reader(f)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Player::new(recording))
+    }
+
+    fn new(mut recording: Recording) -> Player {
+        // Keep the order of the actions within a step
+        recording.actions.sort_by_key(|a| a.step);
+        Player { recording, next: 0 }
+    }
+
+    /// Returns the actions that were applied on `step` and any earlier ones that haven't been
+    /// returned yet. Steps must be requested in order.
+    pub fn actions_at(&mut self, step: usize) -> Vec<Action> {
+        let mut actions = vec![];
+        while let Some(recorded) = self.recording.actions.get(self.next) {
+            if recorded.step > step {
+                break;
+            }
+            if recorded.step < step {
+                warn!(
+                    "replaying {:?} of step {} late on step {}",
+                    recorded.action, recorded.step, step
+                );
+            }
+            actions.push(recorded.action.clone());
+            self.next += 1;
+        }
+        actions
+    }
+}
+
+/// Where the actions of a windowed session come from
+#[derive(Clone, Debug)]
+pub enum InputMode {
+    /// From the window
+    Live,
+    /// From the window, logged into the file
+    Record(PathBuf),
+    /// From a recording in place of the window
+    Replay(PathBuf),
+}
+
+pub const INPUT_USAGE: &str = "usage: ds-sim [--record FILE | --replay FILE]";
+
+impl InputMode {
+    /// Parses the mode from the command line arguments, excluding the program name.
+    pub fn from_args(args: &[String]) -> Result<InputMode, String> {
+        let mut mode = InputMode::Live;
+        let mut it = args.iter();
+        while let Some(arg) = it.next() {
+            let path = it.next()
+                .map(PathBuf::from)
+                .ok_or_else(|| format!("missing value for {}", arg));
+            mode = match arg.as_str() {
+                "--record" => InputMode::Record(path?),
+                "--replay" => InputMode::Replay(path?),
+                other => return Err(format!("unknown argument: {}", other)),
+            };
+        }
+        Ok(mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recording(steps: &[(usize, Action)]) -> Recording {
+        Recording {
+            actions: steps
+                .iter()
+                .map(|&(step, ref action)| RecordedAction {
+                    step,
+                    action: action.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn skipped_steps_are_replayed_late() {
+        let mut player = Player::new(recording(&[
+            (1, Action::Shoot(true)),
+            (2, Action::Shoot(false)),
+            (5, Action::LaunchImpactor),
+        ]));
+        assert_eq!(player.actions_at(0).len(), 0);
+        // Steps 1 and 2 were never requested
+        assert_eq!(player.actions_at(3).len(), 2);
+        assert_eq!(player.actions_at(4).len(), 0);
+        assert_eq!(player.actions_at(5).len(), 1);
+        assert_eq!(player.actions_at(6).len(), 0);
+    }
+
+    #[test]
+    fn refresh_is_recorded_in_order() {
+        let path = ::std::env::temp_dir().join("ds-sim-refresh-is-recorded.yaml");
+        let mut recorder = Recorder::new(&path);
+        recorder.record(3, &[Action::Shoot(true), Action::Refresh]);
+        recorder.record(3, &[Action::Shoot(false)]);
+        let mut player = Player::new(recorder.recording.clone());
+        match player.actions_at(3).as_slice() {
+            &[Action::Shoot(true), Action::Refresh, Action::Shoot(false)] => {}
+            other => panic!("unexpected actions {:?}", other),
+        }
+        drop(recorder);
+        let _ = fs::remove_file(&path);
+    }
+}
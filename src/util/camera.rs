@@ -9,6 +9,7 @@ pub struct Camera {
     yfov: Rad<f32>,
     znear: f32,
     zfar: f32,
+    aspect: f32,
     // Cached perspective-projection
     c_perspective: Matrix4f,
     // Cached view-projection
@@ -32,6 +33,7 @@ impl Camera {
             yfov,
             znear,
             zfar,
+            aspect,
             c_perspective,
             c_view,
         }
@@ -53,7 +55,12 @@ impl Camera {
         self.target = tgt;
     }
 
+    pub fn aspect(&self) -> f32 {
+        self.aspect
+    }
+
     pub fn set_aspect(&mut self, aspect: f32) {
+        self.aspect = aspect;
         self.c_perspective = Camera::get_perspective(self.yfov, aspect, self.znear, self.zfar)
     }
 
@@ -3,7 +3,10 @@
 pub mod camera;
 
 use std::fs::{metadata, File};
+use std::io;
 use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use glium::Display;
 use glium::vertex::{VertexBuffer, VertexBufferAny};
 use obj;
@@ -76,6 +79,29 @@ pub fn load_wavefront(display: &Display, data: &[u8]) -> VertexBufferAny {
         .into_vertex_buffer_any()
 }
 
+/// Reads the triangles of a Wavefront OBJ without creating any GL resources. Quads are split
+/// into two triangles.
+pub fn load_wavefront_triangles(data: &[u8]) -> io::Result<Vec<[[f32; 3]; 3]>> {
+    let mut data = ::std::io::BufReader::new(data);
+    let data = obj::Obj::load_buf(&mut data)?;
+
+    let mut triangles = Vec::new();
+    for object in data.objects.iter() {
+        for polygon in object.groups.iter().flat_map(|g| g.polys.iter()) {
+            match polygon {
+                &genmesh::Polygon::PolyTri(genmesh::Triangle { x, y, z }) => {
+                    triangles.push([data.position[x.0], data.position[y.0], data.position[z.0]]);
+                }
+                &genmesh::Polygon::PolyQuad(genmesh::Quad { x, y, z, w }) => {
+                    triangles.push([data.position[x.0], data.position[y.0], data.position[z.0]]);
+                    triangles.push([data.position[x.0], data.position[z.0], data.position[w.0]]);
+                }
+            }
+        }
+    }
+    Ok(triangles)
+}
+
 /// This is used to reinterpret slices of floats as slices of repr(C) structs, without any
 /// copying. It is optimal, but it is also punching holes in the type system. I hope that Rust
 /// provides safe functionality to handle this in the future. In the meantime, reproduce
@@ -93,3 +119,39 @@ pub fn file_exists(path: &str) -> bool {
     }
     metadata.unwrap().is_file()
 }
+
+/// Polls the modification times of a set of files.
+pub struct FileWatcher {
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl FileWatcher {
+    pub fn new(paths: Vec<PathBuf>) -> FileWatcher {
+        let files = paths
+            .into_iter()
+            .map(|path| {
+                let mtime = modified(&path);
+                (path, mtime)
+            })
+            .collect();
+        FileWatcher { files }
+    }
+
+    /// Returns true if any of the files was modified, created or removed since the last call.
+    pub fn changed(&mut self) -> bool {
+        let mut changed = false;
+        for &mut (ref path, ref mut mtime) in self.files.iter_mut() {
+            let now = modified(path);
+            if now != *mtime {
+                debug!("{} changed", path.display());
+                *mtime = now;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    metadata(path).and_then(|m| m.modified()).ok()
+}